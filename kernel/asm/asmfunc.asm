@@ -19,10 +19,17 @@ IoIn32:
     in eax, dx
     ret
 
-global GetCS  ; uint16_t GetCS(void);
-GetCS:
-    xor eax, eax  ; also clears upper 32 bits of rax
-    mov ax, cs
+global IoOut8  ; void IoOut8(uint16_t addr, uint8_t data);
+IoOut8:
+    mov dx, di    ; dx = addr
+    mov al, sil   ; al = data
+    out dx, al
+    ret
+
+global IoIn8  ; uint8_t IoIn8(uint16_t addr);
+IoIn8:
+    mov dx, di    ; dx = addr
+    in al, dx
     ret
 
 ; #@@range_begin(load_idt_function)
@@ -38,3 +45,155 @@ LoadIDT:
     pop rbp
     ret
 ; #@@range_end(load_idt_function)
+
+global SetCR3  ; void SetCR3(uint64_t value);
+SetCR3:
+    mov cr3, rdi
+    ret
+
+global LoadGDT  ; void LoadGDT(uint16_t limit, uint64_t offset);
+LoadGDT:
+    push rbp
+    mov rbp, rsp
+    sub rsp, 10
+    mov [rsp], di  ; limit
+    mov [rsp + 2], rsi  ; offset
+    lgdt [rsp]
+    mov rsp, rbp
+    pop rbp
+    ret
+
+global LoadTR  ; void LoadTR(uint16_t sel);
+LoadTR:
+    ltr di
+    ret
+
+global SetDSAll  ; void SetDSAll(uint16_t value);
+SetDSAll:
+    mov ds, di
+    mov es, di
+    mov fs, di
+    mov gs, di
+    ret
+
+global SetCSSS  ; void SetCSSS(uint16_t cs, uint16_t ss);
+SetCSSS:
+    push rbp
+    mov rbp, rsp
+    mov ss, si
+    mov rax, .next
+    push rdi    ; CS
+    push rax    ; RIP
+    o64 retf
+.next:
+    mov rsp, rbp
+    pop rbp
+    ret
+
+extern KERNEL_MAIN_STACK
+extern KERNEL_MAIN_STACK_BYTES
+extern KernelMainNewStack
+
+global KernelMain
+KernelMain:
+    ; rdi, rsi, rdx (frame buffer config, memory map, RSDP or 0) are passed through
+    ; the stack grows down from the end of KERNEL_MAIN_STACK (guard page + stack)
+    mov rsp, KERNEL_MAIN_STACK
+    add rsp, [KERNEL_MAIN_STACK_BYTES]
+    call KernelMainNewStack
+.fin:
+    hlt
+    jmp .fin
+
+global ReadMSR  ; uint64_t ReadMSR(uint32_t msr);
+ReadMSR:
+    mov ecx, edi
+    rdmsr
+    shl rdx, 32
+    or rax, rdx
+    ret
+
+global WriteMSR  ; void WriteMSR(uint32_t msr, uint64_t value);
+WriteMSR:
+    mov rdx, rsi
+    shr rdx, 32
+    mov eax, esi
+    mov ecx, edi
+    wrmsr
+    ret
+
+global SwitchContext  ; void SwitchContext(void* next_ctx, void* current_ctx);
+SwitchContext:
+    mov [rsi + 0x40], rax
+    mov [rsi + 0x48], rbx
+    mov [rsi + 0x50], rcx
+    mov [rsi + 0x58], rdx
+    mov [rsi + 0x60], rdi
+    mov [rsi + 0x68], rsi
+
+    lea rax, [rsp + 8]
+    mov [rsi + 0x70], rax  ; RSP (after returning to the caller)
+    mov [rsi + 0x78], rbp
+
+    mov [rsi + 0x80], r8
+    mov [rsi + 0x88], r9
+    mov [rsi + 0x90], r10
+    mov [rsi + 0x98], r11
+    mov [rsi + 0xa0], r12
+    mov [rsi + 0xa8], r13
+    mov [rsi + 0xb0], r14
+    mov [rsi + 0xb8], r15
+
+    mov rax, cr3
+    mov [rsi + 0x00], rax  ; CR3
+    mov rax, [rsp]
+    mov [rsi + 0x08], rax  ; RIP (return address)
+    pushfq
+    pop qword [rsi + 0x10] ; RFLAGS
+
+    xor eax, eax
+    mov ax, cs
+    mov [rsi + 0x20], rax
+    mov ax, ss
+    mov [rsi + 0x28], rax
+    mov ax, fs
+    mov [rsi + 0x30], rax
+    mov ax, gs
+    mov [rsi + 0x38], rax
+
+    fxsave [rsi + 0xc0]
+
+    ; stack frame for iret
+    push qword [rdi + 0x28] ; SS
+    push qword [rdi + 0x70] ; RSP
+    push qword [rdi + 0x10] ; RFLAGS
+    push qword [rdi + 0x20] ; CS
+    push qword [rdi + 0x08] ; RIP
+
+    fxrstor [rdi + 0xc0]
+
+    mov rax, [rdi + 0x00]
+    mov cr3, rax
+    mov rax, [rdi + 0x30]
+    mov fs, ax
+    mov rax, [rdi + 0x38]
+    mov gs, ax
+
+    mov rax, [rdi + 0x40]
+    mov rbx, [rdi + 0x48]
+    mov rcx, [rdi + 0x50]
+    mov rdx, [rdi + 0x58]
+    mov rsi, [rdi + 0x68]
+    mov rbp, [rdi + 0x78]
+    mov r8,  [rdi + 0x80]
+    mov r9,  [rdi + 0x88]
+    mov r10, [rdi + 0x90]
+    mov r11, [rdi + 0x98]
+    mov r12, [rdi + 0xa0]
+    mov r13, [rdi + 0xa8]
+    mov r14, [rdi + 0xb0]
+    mov r15, [rdi + 0xb8]
+
+    mov rdi, [rdi + 0x60]
+
+    o64 iret
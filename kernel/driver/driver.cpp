@@ -2,6 +2,7 @@
 
 #include "usb/memory.hpp"
 #include "usb/device.hpp"
+#include "usb/classdriver/keyboard.hpp"
 #include "usb/classdriver/mouse.hpp"
 #include "usb/xhci/xhci.hpp"
 #include "usb/xhci/trb.hpp"
@@ -24,10 +25,17 @@ extern "C" XHC_HANDLE UsbInitXhc(uint64_t xhc_mmio_base) {
   return 0;
 }
 
-typedef void (*MouseObserverType)(int8_t, int8_t);
+typedef void (*MouseObserverType)(uint8_t, int8_t, int8_t);
+typedef void (*KeyboardObserverType)(uint8_t, uint8_t, bool);
+typedef void (*PortObserverType)(uint8_t, bool);
 
-extern "C" void UsbConfigurePort(XHC_HANDLE xhc_handle, MouseObserverType mouse_observer) {
+extern "C" void UsbConfigurePort(XHC_HANDLE xhc_handle,
+                                 MouseObserverType mouse_observer,
+                                 KeyboardObserverType keyboard_observer,
+                                 PortObserverType port_observer) {
   usb::HIDMouseDriver::default_observer = mouse_observer;
+  usb::HIDKeyboardDriver::default_observer = keyboard_observer;
+  usb::xhci::port_observer = port_observer;
 
   for (int i = 1; i <= xhc->MaxPorts(); ++i) {
     auto port = xhc->PortAt(i);
@@ -10,16 +10,22 @@ namespace usb {
   }
 
   Error HIDKeyboardDriver::OnDataReceived() {
-    for (int i = 2; i < 8; ++i) {
-      const uint8_t key = Buffer()[i];
-      if (key == 0) {
-        continue;
+    // ブートプロトコルのレポートは 0 バイト目が修飾キー，2〜7 バイト目が押されているキー
+    const uint8_t modifier = Buffer()[0];
+    const auto keys = Buffer().begin() + 2;
+    const auto prev_keys = PreviousBuffer().begin() + 2;
+
+    for (int i = 0; i < 6; ++i) {
+      const uint8_t key = prev_keys[i];
+      if (key != 0 && std::find(keys, keys + 6, key) == keys + 6) {
+        NotifyKeyPush(modifier, key, false);
       }
-      const auto& prev_buf = PreviousBuffer();
-      if (std::find(prev_buf.begin(), prev_buf.end(), key) != prev_buf.end()) {
-        continue;
+    }
+    for (int i = 0; i < 6; ++i) {
+      const uint8_t key = keys[i];
+      if (key != 0 && std::find(prev_keys, prev_keys + 6, key) == prev_keys + 6) {
+        NotifyKeyPush(modifier, key, true);
       }
-      NotifyKeyPush(key);
     }
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -33,15 +39,15 @@ namespace usb {
   }
 
   void HIDKeyboardDriver::SubscribeKeyPush(
-      std::function<void (uint8_t keycode)> observer) {
+      std::function<ObserverType> observer) {
     observers_[num_observers_++] = observer;
   }
 
   std::function<HIDKeyboardDriver::ObserverType> HIDKeyboardDriver::default_observer;
 
-  void HIDKeyboardDriver::NotifyKeyPush(uint8_t keycode) {
+  void HIDKeyboardDriver::NotifyKeyPush(uint8_t modifier, uint8_t keycode, bool press) {
     for (int i = 0; i < num_observers_; ++i) {
-      observers_[i](keycode);
+      observers_[i](modifier, keycode, press);
     }
   }
 }
@@ -19,7 +19,8 @@ namespace usb {
 
     Error OnDataReceived() override;
 
-    using ObserverType = void (uint8_t keycode);
+    /** modifier は修飾キーの押下状態，press は押されたなら true，離されたなら false．*/
+    using ObserverType = void (uint8_t modifier, uint8_t keycode, bool press);
     void SubscribeKeyPush(std::function<ObserverType> observer);
     static std::function<ObserverType> default_observer;
 
@@ -27,6 +28,6 @@ namespace usb {
     std::array<std::function<ObserverType>, 4> observers_;
     int num_observers_ = 0;
 
-    void NotifyKeyPush(uint8_t keycode);
+    void NotifyKeyPush(uint8_t modifier, uint8_t keycode, bool press);
   };
 }
@@ -9,12 +9,10 @@
 #include <cstddef>
 
 namespace usb {
-  /** @brief 動的メモリ確保のためのメモリプールの最大容量（バイト） */
-  static const size_t kMemoryPoolSize = 4096 * 32;
-
   /** @brief 指定されたバイト数のメモリ領域を確保して先頭ポインタを返す．
    *
-   * 先頭アドレスが alignment に揃ったメモリ領域を確保する．
+   * カーネルの DMA 用メモリアロケータ（DmaAllocate）から，
+   * 物理的に連続し先頭アドレスが alignment に揃ったメモリ領域を確保する．
    * size <= boundary ならメモリ領域が boundary を跨がないことを保証する．
    * boundary は典型的にはページ境界を跨がないように 4096 を指定する．
    *
@@ -31,7 +29,7 @@ namespace usb {
         AllocMem(sizeof(T) * num_obj, alignment, boundary));
   }
 
-  /** @brief 指定されたメモリ領域を解放する．本当に解放することは保証されない． */
+  /** @brief AllocMem で確保したメモリ領域を解放する． */
   void FreeMem(void* p);
 
   /** @brief 標準コンテナ用のメモリアロケータ */
@@ -42,6 +42,12 @@ namespace {
    */
   uint8_t addressing_port{0};
 
+  void NotifyPortObserver(uint8_t port_id, bool connected) {
+    if (port_observer) {
+      port_observer(port_id, connected);
+    }
+  }
+
   void InitializeSlotContext(SlotContext& ctx, Port& port) {
     ctx.bits.route_string = 0;
     ctx.bits.root_hub_port_num = port.Number();
@@ -109,6 +115,17 @@ namespace {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  /* アドレス割り当てを待っているポートがあれば，1 つだけリセットを始める */
+  Error ResetNextWaitingPort(Controller& xhc) {
+    for (int i = 0; i < port_config_phase.size(); ++i) {
+      if (port_config_phase[i] == ConfigPhase::kWaitingAddressed) {
+        auto port = xhc.PortAt(i);
+        return ResetPort(xhc, port);
+      }
+    }
+    return MAKE_ERROR(Error::kSuccess);
+  }
+
   Error EnableSlot(Controller& xhc, Port& port) {
     const bool is_enabled = port.IsEnabled();
     const bool reset_completed = port.IsPortResetChanged();
@@ -188,6 +205,7 @@ namespace {
     dev->OnEndpointsConfigured();
 
     port_config_phase[port_id] = ConfigPhase::kConfigured;
+    NotifyPortObserver(port_id, true);
     return MAKE_ERROR(Error::kSuccess);
   }
 
@@ -196,6 +214,25 @@ namespace {
     auto port_id = trb.bits.port_id;
     auto port = xhc.PortAt(port_id);
 
+    if (!port.IsConnected() && port.IsConnectStatusChanged()) {
+      port.ClearConnectStatusChanged();
+      /* スロットの無効化はまだ実装していない．どの段階のポートも未接続に戻し，
+       * 再接続されたら新しいスロットで初期化し直す．
+       */
+      const ConfigPhase phase = port_config_phase[port_id];
+      port_config_phase[port_id] = ConfigPhase::kNotConnected;
+      if (phase == ConfigPhase::kConfigured) {
+        /* 接続を通知したポートだけ切断も通知する */
+        NotifyPortObserver(port_id, false);
+      }
+      if (addressing_port == port_id) {
+        /* アドレス割り当ての途中で外されたので，待っている他のポートに譲る */
+        addressing_port = 0;
+        return ResetNextWaitingPort(xhc);
+      }
+      return MAKE_ERROR(Error::kSuccess);
+    }
+
     switch (port_config_phase[port_id]) {
     case ConfigPhase::kNotConnected:
       return ResetPort(xhc, port);
@@ -252,14 +289,8 @@ namespace {
       }
 
       addressing_port = 0;
-      for (int i = 0; i < port_config_phase.size(); ++i) {
-        if (port_config_phase[i] == ConfigPhase::kWaitingAddressed) {
-          auto port = xhc.PortAt(i);
-          if (auto err = ResetPort(xhc, port); err) {
-            return err;
-          }
-          break;
-        }
+      if (auto err = ResetNextWaitingPort(xhc); err) {
+        return err;
       }
 
       return InitializeDevice(xhc, port_id, slot_id);
@@ -413,6 +444,8 @@ namespace usb::xhci {
     return &DoorbellRegisters()[index];
   }
 
+  std::function<PortObserverType> port_observer;
+
   Error ConfigurePort(Controller& xhc, Port& port) {
     if (port_config_phase[port.Number()] == ConfigPhase::kNotConnected) {
       return ResetPort(xhc, port);
@@ -6,6 +6,8 @@
 
 #pragma once
 
+#include <functional>
+
 #include "error.hpp"
 #include "usb/xhci/registers.hpp"
 #include "usb/xhci/context.hpp"
@@ -53,6 +55,12 @@ namespace usb::xhci {
     }
   };
 
+  /** ポートにデバイスが接続されて使えるようになったとき（connected = true）と，
+   * 切断されたとき（connected = false）に呼ばれる．
+   */
+  using PortObserverType = void (uint8_t port_num, bool connected);
+  extern std::function<PortObserverType> port_observer;
+
   Error ConfigurePort(Controller& xhc, Port& port);
   Error ConfigureEndpoints(Controller& xhc, Device& dev);
 
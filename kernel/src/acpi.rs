@@ -0,0 +1,289 @@
+//! ACPI テーブルを扱うプログラムを集めたファイル．
+//!
+//! UEFI ローダから受け取った RSDP を起点に XSDT（古いファームウェアでは RSDT）を辿り，
+//! 署名でテーブルを探す．MADT については割り込みコントローラの情報を取り出せる．
+#![allow(dead_code)]
+
+use crate::error::{Code, Error};
+use crate::make_error;
+use crate::sync::OnceCell;
+use core::fmt;
+
+/// Root System Description Pointer
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+impl Rsdp {
+    /// 署名とチェックサムを確認する
+    fn is_valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " {
+            return false;
+        }
+        // ACPI 1.0 の RSDP は先頭 20 バイトだけ
+        if sum_bytes(self as *const Rsdp as *const u8, 20) != 0 {
+            return false;
+        }
+        self.revision < 2 || sum_bytes(self as *const Rsdp as *const u8, 36) == 0
+    }
+}
+
+/// 各テーブル共通のヘッダ
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct DescriptionHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl DescriptionHeader {
+    pub fn signature(&self) -> &[u8; 4] {
+        &self.signature
+    }
+
+    /// ヘッダを含むテーブル全体の大きさ（バイト）
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    fn is_valid(&self, signature: &[u8; 4]) -> bool {
+        &self.signature == signature
+            && sum_bytes(self as *const DescriptionHeader as *const u8, self.length()) == 0
+    }
+
+    /// ヘッダに続くデータの先頭アドレス
+    fn body(&self) -> *const u8 {
+        unsafe {
+            (self as *const DescriptionHeader as *const u8)
+                .add(core::mem::size_of::<DescriptionHeader>())
+        }
+    }
+
+    /// ヘッダに続くデータの大きさ（バイト）
+    fn body_len(&self) -> usize {
+        self.length()
+            .saturating_sub(core::mem::size_of::<DescriptionHeader>())
+    }
+}
+
+impl fmt::Display for DescriptionHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let signature = self.signature;
+        write!(
+            f,
+            "{} ({} bytes)",
+            core::str::from_utf8(&signature).unwrap_or("????"),
+            self.length()
+        )
+    }
+}
+
+fn sum_bytes(p: *const u8, len: usize) -> u8 {
+    let bytes = unsafe { core::slice::from_raw_parts(p, len) };
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// XSDT または RSDT
+#[derive(Debug, Copy, Clone)]
+struct RootTable {
+    header: &'static DescriptionHeader,
+    /// エントリ 1 つの大きさ．XSDT なら 8，RSDT なら 4．
+    entry_size: usize,
+}
+
+impl RootTable {
+    fn entries(&self) -> impl Iterator<Item = &'static DescriptionHeader> {
+        let body = self.header.body();
+        let entry_size = self.entry_size;
+        (0..self.header.body_len() / entry_size).map(move |i| unsafe {
+            let p = body.add(i * entry_size);
+            let addr = if entry_size == 8 {
+                core::ptr::read_unaligned(p as *const u64)
+            } else {
+                core::ptr::read_unaligned(p as *const u32) as u64
+            };
+            &*(addr as *const DescriptionHeader)
+        })
+    }
+}
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::new();
+
+/// RSDP を検証し，XSDT（なければ RSDT）を登録する
+///
+/// ACPI テーブルはブートサービス領域にはないが，RSDP はあり得るので，
+/// ブートサービス領域を再利用する前に呼び出すこと．
+pub fn initialize(rsdp: &'static Rsdp) -> Result<(), Error> {
+    if !rsdp.is_valid() {
+        return Err(make_error!(Code::InvalidFormat));
+    }
+
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        let header = unsafe { &*(rsdp.xsdt_address as *const DescriptionHeader) };
+        if !header.is_valid(b"XSDT") {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        RootTable {
+            header,
+            entry_size: 8,
+        }
+    } else {
+        let header = unsafe { &*(rsdp.rsdt_address as u64 as *const DescriptionHeader) };
+        if !header.is_valid(b"RSDT") {
+            return Err(make_error!(Code::InvalidFormat));
+        }
+        RootTable {
+            header,
+            entry_size: 4,
+        }
+    };
+
+    ROOT_TABLE.set(root)
+}
+
+/// XSDT に登録されているテーブルを列挙する
+pub fn tables() -> impl Iterator<Item = &'static DescriptionHeader> {
+    ROOT_TABLE.get().into_iter().flat_map(|root| root.entries())
+}
+
+/// 署名が一致し，チェックサムが正しいテーブルを探す
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static DescriptionHeader> {
+    tables().find(|header| header.is_valid(signature))
+}
+
+/// Multiple APIC Description Table
+#[derive(Debug, Copy, Clone)]
+pub struct Madt {
+    header: &'static DescriptionHeader,
+}
+
+/// MADT に含まれる割り込みコントローラの情報
+#[derive(Debug, Copy, Clone)]
+pub enum MadtEntry {
+    /// Processor Local APIC
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    /// I/O APIC
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// ISA の IRQ が GSI にどう繋がっているか
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    /// Local APIC の LINT に繋がった NMI
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    /// このカーネルが解釈しない種類のエントリ
+    Unknown { entry_type: u8 },
+}
+
+/// MADT の先頭からエントリの並びまでの大きさ（Local APIC アドレスとフラグ）
+const MADT_FIXED_LEN: usize = 8;
+
+impl Madt {
+    /// XSDT から MADT を探す
+    pub fn find() -> Result<Self, Error> {
+        let header = find_table(b"APIC").ok_or(make_error!(Code::NotFound))?;
+        Ok(Madt { header })
+    }
+
+    /// Local APIC の物理アドレス
+    pub fn local_apic_address(&self) -> u32 {
+        unsafe { core::ptr::read_unaligned(self.header.body() as *const u32) }
+    }
+
+    /// 真なら 8259 PIC も搭載されている
+    pub fn pcat_compatible(&self) -> bool {
+        let flags = unsafe { core::ptr::read_unaligned(self.header.body().add(4) as *const u32) };
+        flags & 1 != 0
+    }
+
+    pub fn entries(&self) -> MadtIter {
+        let body = self.header.body();
+        let len = self.header.body_len();
+        MadtIter {
+            p: unsafe { body.add(MADT_FIXED_LEN) },
+            end: unsafe { body.add(len) },
+        }
+    }
+}
+
+pub struct MadtIter {
+    p: *const u8,
+    end: *const u8,
+}
+
+impl Iterator for MadtIter {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        if (self.p as usize) + 2 > self.end as usize {
+            return None;
+        }
+        let (entry_type, len) = unsafe { (*self.p, *self.p.add(1) as usize) };
+        if len < 2 || (self.p as usize) + len > self.end as usize {
+            // 壊れたエントリ以降は読まない
+            self.p = self.end;
+            return None;
+        }
+
+        let p = self.p;
+        let u8_at = |offset: usize| unsafe { *p.add(offset) };
+        let u16_at =
+            |offset: usize| unsafe { core::ptr::read_unaligned(p.add(offset) as *const u16) };
+        let u32_at =
+            |offset: usize| unsafe { core::ptr::read_unaligned(p.add(offset) as *const u32) };
+        let entry = match (entry_type, len) {
+            (0, 8) => MadtEntry::LocalApic {
+                processor_id: u8_at(2),
+                apic_id: u8_at(3),
+                flags: u32_at(4),
+            },
+            (1, 12) => MadtEntry::IoApic {
+                id: u8_at(2),
+                address: u32_at(4),
+                gsi_base: u32_at(8),
+            },
+            (2, 10) => MadtEntry::InterruptSourceOverride {
+                bus: u8_at(2),
+                source: u8_at(3),
+                gsi: u32_at(4),
+                flags: u16_at(8),
+            },
+            (4, 6) => MadtEntry::LocalApicNmi {
+                processor_id: u8_at(2),
+                flags: u16_at(3),
+                lint: u8_at(5),
+            },
+            _ => MadtEntry::Unknown { entry_type },
+        };
+
+        self.p = unsafe { self.p.add(len) };
+        Some(entry)
+    }
+}
@@ -1,8 +1,17 @@
-use cty::{uint16_t, uint32_t, uint64_t};
+use cty::{c_void, uint16_t, uint32_t, uint64_t, uint8_t};
 
 extern "C" {
     pub fn IoOut32(addr: uint16_t, data: uint32_t);
     pub fn IoIn32(addr: uint16_t) -> uint32_t;
-    pub fn GetCS() -> uint16_t;
+    pub fn IoOut8(addr: uint16_t, data: uint8_t);
+    pub fn IoIn8(addr: uint16_t) -> uint8_t;
     pub fn LoadIDT(limit: uint16_t, offset: uint64_t);
+    pub fn SetCR3(value: uint64_t);
+    pub fn LoadGDT(limit: uint16_t, offset: uint64_t);
+    pub fn LoadTR(sel: uint16_t);
+    pub fn SetDSAll(value: uint16_t);
+    pub fn SetCSSS(cs: uint16_t, ss: uint16_t);
+    pub fn ReadMSR(msr: uint32_t) -> uint64_t;
+    pub fn WriteMSR(msr: uint32_t, value: uint64_t);
+    pub fn SwitchContext(next_ctx: *const c_void, current_ctx: *mut c_void);
 }
@@ -0,0 +1,306 @@
+//! デバイスドライバ向けの DMA 用メモリ管理機能．
+//!
+//! 物理的に連続し，アライメントと境界の制約を満たすメモリ領域を確保する．
+//! C++ で書かれた USB ドライバからも DmaAllocate / DmaFree として呼び出せる．
+#![allow(dead_code)]
+
+use crate::error::{Code, Error};
+use crate::global;
+use crate::make_error;
+use crate::memory_manager::{FrameID, BYTES_PER_FRAME};
+use crate::paging;
+use crate::sync::SpinLock;
+use alloc::collections::BTreeMap;
+use core::fmt;
+use cty::{c_uint, c_void, size_t, uint64_t};
+use log::warn;
+
+/// 小さな要求をまとめて割り当てるためのチャンク 1 つあたりのフレーム数（64 KiB）
+const CHUNK_FRAMES: usize = 16;
+const CHUNK_BYTES: u64 = CHUNK_FRAMES as u64 * BYTES_PER_FRAME;
+
+/// 確保した DMA 用メモリ領域
+#[derive(Debug, Copy, Clone)]
+pub struct DmaBuffer {
+    /// CPU からアクセスするための仮想アドレス
+    pub virt: *mut u8,
+    /// デバイスに渡す物理アドレス
+    pub phys: u64,
+    /// 要求された大きさ（バイト）
+    pub size: usize,
+}
+
+/// DMA 用メモリの使用状況
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DmaStat {
+    /// 確保中の領域の数
+    pub num_allocations: usize,
+    /// 確保中の領域の大きさの合計（バイト）
+    pub allocated_bytes: usize,
+    /// フレームアロケータから借りているフレーム数
+    pub reserved_frames: usize,
+}
+
+impl fmt::Display for DmaStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations, {} bytes in use, {} frames reserved",
+            self.num_allocations, self.allocated_bytes, self.reserved_frames
+        )
+    }
+}
+
+/// 確保した領域がどこから切り出されたか
+#[derive(Debug, Copy, Clone)]
+enum Origin {
+    /// チャンクから切り出した小さな領域
+    Chunk(u64),
+    /// 専用に確保したフレーム
+    Frames(usize),
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Allocation {
+    size: usize,
+    origin: Origin,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Chunk {
+    /// 次に割り当てる位置
+    alloc_ptr: u64,
+    /// このチャンクから切り出して，まだ解放されていない領域の数
+    live: usize,
+}
+
+/// DMA 用メモリアロケータ
+///
+/// チャンクより小さい要求は 64 KiB 境界に揃ったチャンクから切り出し，
+/// それ以外の要求には専用のフレームを確保する．
+struct DmaAllocator {
+    /// 確保中の領域．キーは先頭の仮想アドレス．
+    allocations: BTreeMap<u64, Allocation>,
+    /// 使用中のチャンク．キーはチャンクの先頭アドレス．
+    chunks: BTreeMap<u64, Chunk>,
+    /// 新たな要求を切り出す対象のチャンク
+    current_chunk: Option<u64>,
+    stat: DmaStat,
+}
+
+static DMA_ALLOCATOR: SpinLock<DmaAllocator> = SpinLock::new(DmaAllocator::new());
+
+const fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+impl DmaAllocator {
+    const fn new() -> Self {
+        DmaAllocator {
+            allocations: BTreeMap::new(),
+            chunks: BTreeMap::new(),
+            current_chunk: None,
+            stat: DmaStat {
+                num_allocations: 0,
+                allocated_bytes: 0,
+                reserved_frames: 0,
+            },
+        }
+    }
+
+    fn allocate(&mut self, size: usize, alignment: u64, boundary: u64) -> Result<u64, Error> {
+        if size == 0
+            || (alignment != 0 && !alignment.is_power_of_two())
+            || (boundary != 0 && !boundary.is_power_of_two())
+        {
+            return Err(make_error!(Code::InvalidDescriptor));
+        }
+        // boundary より大きな領域は境界を跨がざるを得ないので，制約しない
+        let boundary = if size as u64 > boundary { 0 } else { boundary };
+
+        let alignment = core::cmp::max(alignment, 1);
+        let (addr, origin) = if size as u64 <= CHUNK_BYTES / 4 && alignment <= BYTES_PER_FRAME {
+            self.allocate_from_chunk(size as u64, alignment, boundary)?
+        } else {
+            self.allocate_frames(size as u64, alignment, boundary)?
+        };
+
+        self.allocations.insert(addr, Allocation { size, origin });
+        self.stat.num_allocations += 1;
+        self.stat.allocated_bytes += size;
+        Ok(addr)
+    }
+
+    /// チャンクから小さな領域を切り出す
+    fn allocate_from_chunk(
+        &mut self,
+        size: u64,
+        alignment: u64,
+        boundary: u64,
+    ) -> Result<(u64, Origin), Error> {
+        if let Some(addr) = self.current_chunk.and_then(|base| {
+            let chunk = &self.chunks[&base];
+            fit_in(
+                chunk.alloc_ptr,
+                base + CHUNK_BYTES,
+                size,
+                alignment,
+                boundary,
+            )
+        }) {
+            let base = self.current_chunk.unwrap();
+            let chunk = self.chunks.get_mut(&base).unwrap();
+            chunk.alloc_ptr = addr + size;
+            chunk.live += 1;
+            return Ok((addr, Origin::Chunk(base)));
+        }
+
+        // 新しいチャンクは 64 KiB 境界に揃えるので，チャンクの先頭からなら必ず収まる
+        let base = global::memory_manager()
+            .allocate_aligned(CHUNK_FRAMES, CHUNK_FRAMES)?
+            .frame() as u64;
+        self.stat.reserved_frames += CHUNK_FRAMES;
+        let addr = fit_in(base, base + CHUNK_BYTES, size, alignment, boundary)
+            .ok_or(make_error!(Code::NoEnoughMemory))?;
+        self.chunks.insert(
+            base,
+            Chunk {
+                alloc_ptr: addr + size,
+                live: 1,
+            },
+        );
+        self.current_chunk = Some(base);
+        Ok((addr, Origin::Chunk(base)))
+    }
+
+    /// 専用のフレームを確保する
+    ///
+    /// 先頭を size 以上の 2 のべき乗に揃えれば，size 以下の境界は跨がない．
+    fn allocate_frames(
+        &mut self,
+        size: u64,
+        alignment: u64,
+        boundary: u64,
+    ) -> Result<(u64, Origin), Error> {
+        let num_frames = ((size + BYTES_PER_FRAME - 1) / BYTES_PER_FRAME) as usize;
+        let mut align_bytes = core::cmp::max(alignment, BYTES_PER_FRAME);
+        if boundary != 0 {
+            align_bytes = core::cmp::max(align_bytes, size.next_power_of_two());
+        }
+        let align_frames = (align_bytes / BYTES_PER_FRAME) as usize;
+
+        let frame = global::memory_manager().allocate_aligned(num_frames, align_frames)?;
+        self.stat.reserved_frames += num_frames;
+        Ok((frame.frame() as u64, Origin::Frames(num_frames)))
+    }
+
+    fn free(&mut self, addr: u64) -> Result<(), Error> {
+        let allocation = self
+            .allocations
+            .remove(&addr)
+            .ok_or(make_error!(Code::InvalidDescriptor))?;
+        self.stat.num_allocations -= 1;
+        self.stat.allocated_bytes -= allocation.size;
+
+        match allocation.origin {
+            Origin::Frames(num_frames) => {
+                global::memory_manager().free(FrameID::from_addr(addr), num_frames)?;
+                self.stat.reserved_frames -= num_frames;
+            }
+            Origin::Chunk(base) => {
+                let chunk = self.chunks.get_mut(&base).unwrap();
+                chunk.live -= 1;
+                if chunk.live == 0 {
+                    // 切り出した領域がすべて解放されたらチャンクごと返却する
+                    self.chunks.remove(&base);
+                    if self.current_chunk == Some(base) {
+                        self.current_chunk = None;
+                    }
+                    global::memory_manager().free(FrameID::from_addr(base), CHUNK_FRAMES)?;
+                    self.stat.reserved_frames -= CHUNK_FRAMES;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [ptr, end) の中に，制約を満たす size バイトの領域を探して先頭アドレスを返す
+fn fit_in(ptr: u64, end: u64, size: u64, alignment: u64, boundary: u64) -> Option<u64> {
+    let mut addr = align_up(ptr, alignment);
+    if boundary != 0 {
+        let next_boundary = align_up(addr + 1, boundary);
+        if next_boundary < addr + size {
+            addr = align_up(next_boundary, alignment);
+        }
+    }
+    if addr + size <= end {
+        Some(addr)
+    } else {
+        None
+    }
+}
+
+/// 物理的に連続した DMA 用メモリ領域を確保する
+///
+/// * `size` - 確保する大きさ（バイト）
+/// * `alignment` - 先頭アドレスのアライメント．0 なら制約しない．
+/// * `boundary` - 跨いではいけない境界（例えば 64 KiB）．0 なら制約しない．
+///   size <= boundary の場合に限り，境界を跨がないことを保証する．
+pub fn allocate(size: usize, alignment: u64, boundary: u64) -> Result<DmaBuffer, Error> {
+    let virt = DMA_ALLOCATOR.lock().allocate(size, alignment, boundary)?;
+    let phys = paging::translate(virt).ok_or(make_error!(Code::InvalidDescriptor))?;
+    unsafe {
+        core::ptr::write_bytes(virt as *mut u8, 0, size);
+    }
+    Ok(DmaBuffer {
+        virt: virt as *mut u8,
+        phys,
+        size,
+    })
+}
+
+/// allocate() で確保した領域を解放する
+pub fn free(buffer: DmaBuffer) -> Result<(), Error> {
+    DMA_ALLOCATOR.lock().free(buffer.virt as u64)
+}
+
+pub fn stat() -> DmaStat {
+    DMA_ALLOCATOR.lock().stat
+}
+
+/// C++ のドライバ向けの DMA 用メモリ確保関数
+///
+/// 確保できなかった場合は NULL を返す．phys が NULL でなければ物理アドレスを書き込む．
+#[no_mangle]
+pub extern "C" fn DmaAllocate(
+    size: size_t,
+    alignment: c_uint,
+    boundary: c_uint,
+    phys: *mut uint64_t,
+) -> *mut c_void {
+    match allocate(size, alignment as u64, boundary as u64) {
+        Ok(buffer) => {
+            if !phys.is_null() {
+                unsafe {
+                    *phys = buffer.phys;
+                }
+            }
+            buffer.virt as *mut c_void
+        }
+        Err(_) => core::ptr::null_mut(),
+    }
+}
+
+/// C++ のドライバ向けの DMA 用メモリ解放関数
+#[no_mangle]
+pub extern "C" fn DmaFree(p: *mut c_void) {
+    if p.is_null() {
+        return;
+    }
+    // 不正なポインタや二重解放はドライバのバグなので，握りつぶさずに報告する
+    let result = DMA_ALLOCATOR.lock().free(p as u64);
+    if let Err(e) = result {
+        warn!("DmaFree({:p}): {}\n", p, e);
+    }
+}
@@ -2,7 +2,12 @@ use crate::printk;
 use cstr_core::{c_char, CStr};
 use cty::{c_int, uint64_t};
 
-pub type MouseObserverFn = extern "C" fn(i8, i8);
+/// (ボタンの押下状態, x 方向の移動量, y 方向の移動量)
+pub type MouseObserverFn = extern "C" fn(u8, i8, i8);
+/// (修飾キーの押下状態, キーコード, 押されたなら true)
+pub type KeyboardObserverFn = extern "C" fn(u8, u8, bool);
+/// (ポート番号, 接続されたなら true)
+pub type PortObserverFn = extern "C" fn(u8, bool);
 pub type XhcHandle = c_int;
 
 #[allow(dead_code)]
@@ -18,7 +23,12 @@ pub enum LogLevel {
 extern "C" {
     pub fn SetLogLevel(level: LogLevel);
     pub fn UsbInitXhc(xhc_mmio_base: uint64_t) -> XhcHandle;
-    pub fn UsbConfigurePort(xhc_handle: XhcHandle, mouse_observer: MouseObserverFn);
+    pub fn UsbConfigurePort(
+        xhc_handle: XhcHandle,
+        mouse_observer: MouseObserverFn,
+        keyboard_observer: KeyboardObserverFn,
+        port_observer: PortObserverFn,
+    );
     pub fn UsbReceiveEvent(xhc_handle: XhcHandle);
 
     pub fn GetLog() -> *const c_char;
@@ -1,7 +1,7 @@
 use core::fmt;
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Code {
     Success,
     Full,
@@ -24,6 +24,8 @@ pub enum Code {
     UnknownXHCISpeedID,
     NoWaiter,
     NoPCIMSI,
+    InvalidFormat,
+    NotFound,
     LastOfCode, // この列挙子は常に最後に配置する
 }
 
@@ -38,6 +40,10 @@ impl Error {
     pub fn new(code: Code, file: &'static str, line: u32) -> Self {
         Error { code, file, line }
     }
+
+    pub fn code(&self) -> Code {
+        self.code
+    }
 }
 
 impl fmt::Display for Error {
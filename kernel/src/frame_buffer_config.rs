@@ -8,6 +8,7 @@ pub enum PixelFormat {
 }
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct FrameBufferConfig {
     frame_buffer: *const uint8_t,
     pixels_per_scan_line: uint32_t,
@@ -16,6 +17,10 @@ pub struct FrameBufferConfig {
     pixel_format: PixelFormat,
 }
 
+// フレームバッファはカーネル全体で共有する領域で，設定自体は起動後に変わらない
+unsafe impl Send for FrameBufferConfig {}
+unsafe impl Sync for FrameBufferConfig {}
+
 impl FrameBufferConfig {
     pub fn frame_buffer(&self) -> *mut u8 {
         self.frame_buffer as *mut u8
@@ -33,6 +38,11 @@ impl FrameBufferConfig {
         self.vertical_resolution as u32
     }
 
+    /// フレームバッファ全体の大きさ（バイト）
+    pub fn frame_buffer_size(&self) -> u64 {
+        4 * self.pixels_per_scan_line as u64 * self.vertical_resolution as u64
+    }
+
     pub fn pixel_format(&self) -> PixelFormat {
         self.pixel_format
     }
@@ -0,0 +1,121 @@
+//! カーネルヒープと alloc クレート用のグローバルアロケータ．
+#![allow(dead_code)]
+
+use crate::error::{Code, Error};
+use crate::make_error;
+use crate::memory_manager::{BitmapMemoryManager, BYTES_PER_FRAME};
+use crate::sync::SpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+/// ヒープとして確保するフレーム数の上限（128 MiB）
+pub const HEAP_FRAMES: usize = 64 * 512;
+/// ヒープとして確保するフレーム数の下限（4 MiB）
+pub const MIN_HEAP_FRAMES: usize = 2 * 512;
+
+/// ヒープの使用状況
+#[derive(Debug, Copy, Clone)]
+pub struct HeapStat {
+    pub used: usize,
+    pub free: usize,
+    pub size: usize,
+}
+
+impl fmt::Display for HeapStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "used {} KiB / free {} KiB / total {} KiB",
+            self.used / 1024,
+            self.free / 1024,
+            self.size / 1024
+        )
+    }
+}
+
+/// フレームアロケータから確保した領域を管理するヒープ．
+///
+/// 割り込みハンドラからも確保される可能性があるため，
+/// ヒープ操作の間は割り込みを禁止する．
+pub struct KernelHeap {
+    heap: SpinLock<Heap>,
+}
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        KernelHeap {
+            heap: SpinLock::new(Heap::empty()),
+        }
+    }
+
+    pub fn stat(&self) -> HeapStat {
+        let heap = self.heap.lock();
+        HeapStat {
+            used: heap.used(),
+            free: heap.free(),
+            size: heap.size(),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.heap
+            .lock()
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |p| p.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(p) = NonNull::new(ptr) {
+            self.heap.lock().deallocate(p, layout);
+        }
+    }
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap::new();
+
+pub fn stat() -> HeapStat {
+    HEAP.stat()
+}
+
+/// フレームアロケータから領域を確保し，ヒープとして登録する
+///
+/// 空きフレームの半分（最大 HEAP_FRAMES）を確保する．連続した領域が取れなければ
+/// 半分ずつ小さくして試し，MIN_HEAP_FRAMES も取れなければエラーを返す．
+pub fn initialize_heap(memory_manager: &mut BitmapMemoryManager) -> Result<(), Error> {
+    if stat().size != 0 {
+        return Err(make_error!(Code::AlreadyAllocated));
+    }
+
+    let mut num_frames = core::cmp::min(HEAP_FRAMES, memory_manager.stat().free_frames() / 2);
+    let heap_start = loop {
+        if num_frames < MIN_HEAP_FRAMES {
+            return Err(make_error!(Code::NoEnoughMemory));
+        }
+        match memory_manager.allocate(num_frames) {
+            Ok(frame) => break frame,
+            Err(_) => num_frames /= 2,
+        }
+    };
+    unsafe {
+        HEAP.heap
+            .lock()
+            .init(heap_start.frame(), num_frames * BYTES_PER_FRAME as usize);
+    }
+    Ok(())
+}
+
+/// メモリ確保に失敗した場合に呼ばれる
+pub fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "{}: size = {}, align = {}, heap: {}",
+        make_error!(Code::NoEnoughMemory),
+        layout.size(),
+        layout.align(),
+        stat()
+    );
+}
@@ -1,7 +1,17 @@
 //! 割り込み用のプログラムを集めたファイル．
 #![allow(dead_code)]
 
+use crate::asm;
+use crate::error::{Code, Error};
+use crate::local_apic;
+use crate::make_error;
+use crate::printk;
+use crate::segment;
+use crate::sync::SpinLock;
+use alloc::vec::Vec;
 use bit_field::BitField;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use cty::{uint16_t, uint32_t, uint64_t};
 use modular_bitfield::prelude::*;
 
@@ -87,9 +97,28 @@ pub fn set_idt_entry(
     desc.segment_selector = segment_selector;
 }
 
+/// IDT を CPU に登録する
+pub fn load_idt() {
+    let idt = idt();
+    unsafe {
+        asm::LoadIDT(
+            (core::mem::size_of_val(idt) - 1) as uint16_t,
+            &idt[0] as *const InterruptDescriptor as uint64_t,
+        );
+    }
+}
+
 pub mod vector {
     pub enum Number {
-        XHCI = 0x40,
+        NMI = 0x02,
+        DoubleFault = 0x08,
+        PageFault = 0x0e,
+        /// 8259 PIC のスプリアス割り込み（pic::MASTER_VECTOR_BASE + 7）
+        PICSpuriousMaster = 0x27,
+        /// 8259 PIC のスプリアス割り込み（pic::SLAVE_VECTOR_BASE + 7）
+        PICSpuriousSlave = 0x2f,
+        LAPICTimer = 0x41,
+        LAPICSpurious = 0xff,
     }
 }
 
@@ -102,9 +131,524 @@ pub struct InterruptFrame {
     ss: uint64_t,
 }
 
-pub fn notify_end_of_interrupt() {
-    let end_of_interrupt = 0xfee000b0usize as *mut u32;
+impl fmt::Display for InterruptFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (rip, cs, rflags, rsp, ss) = (self.rip, self.cs, self.rflags, self.rsp, self.ss);
+        write!(
+            f,
+            "RIP = {:016x}, CS = {:04x}, RFLAGS = {:016x}\nRSP = {:016x}, SS = {:04x}",
+            rip, cs, rflags, rsp, ss
+        )
+    }
+}
+
+/// CPU 例外の数．ベクタ番号 0 から 31 は CPU 例外用に予約されている．
+pub const NUM_EXCEPTIONS: usize = 32;
+
+const EXCEPTION_NAMES: [&str; NUM_EXCEPTIONS] = [
+    "#DE Divide Error",
+    "#DB Debug",
+    "NMI Interrupt",
+    "#BP Breakpoint",
+    "#OF Overflow",
+    "#BR BOUND Range Exceeded",
+    "#UD Invalid Opcode",
+    "#NM Device Not Available",
+    "#DF Double Fault",
+    "Coprocessor Segment Overrun",
+    "#TS Invalid TSS",
+    "#NP Segment Not Present",
+    "#SS Stack-Segment Fault",
+    "#GP General Protection",
+    "#PF Page Fault",
+    "Reserved (15)",
+    "#MF x87 FPU Floating-Point Error",
+    "#AC Alignment Check",
+    "#MC Machine Check",
+    "#XM SIMD Floating-Point Exception",
+    "#VE Virtualization Exception",
+    "#CP Control Protection Exception",
+    "Reserved (22)",
+    "Reserved (23)",
+    "Reserved (24)",
+    "Reserved (25)",
+    "Reserved (26)",
+    "Reserved (27)",
+    "#HV Hypervisor Injection Exception",
+    "#VC VMM Communication Exception",
+    "#SX Security Exception",
+    "Reserved (31)",
+];
+
+/// ページフォルトを起こした線形アドレス（CR2）を読み取る
+fn read_cr2() -> u64 {
+    let cr2: u64;
     unsafe {
-        core::ptr::write_volatile(end_of_interrupt, 0);
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
+    }
+    cr2
+}
+
+/// 例外の内容を表示して CPU を停止する
+fn report_exception(vector: usize, error_code: Option<u64>, frame: &InterruptFrame) -> ! {
+    crate::unlock_console_for_panic();
+    printk!("Exception {:#04x}: {}\n", vector, EXCEPTION_NAMES[vector]);
+    if let Some(error_code) = error_code {
+        printk!("error code = {:#x}\n", error_code);
+    }
+    if vector == vector::Number::PageFault as usize {
+        printk!("CR2 = {:016x}\n", read_cr2());
+    }
+    printk!("{}\n", frame);
+    loop {
+        crate::hlt();
     }
 }
+
+macro_rules! fault_handler {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(frame: *const InterruptFrame) {
+            report_exception($vector, None, unsafe { &*frame });
+        }
+    };
+    ($name:ident, $vector:expr, error_code) => {
+        extern "x86-interrupt" fn $name(frame: *const InterruptFrame, error_code: u64) {
+            report_exception($vector, Some(error_code), unsafe { &*frame });
+        }
+    };
+}
+
+/// 受け取った NMI の数
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+/// take_new_nmis() で最後に報告した NMI の数
+static NMI_REPORTED: AtomicU64 = AtomicU64::new(0);
+
+/// NMI は致命的とは限らないので，数えるだけで処理を続ける
+///
+/// NMI は割り込み禁止中にも発生するので，コンソールのロックを取って表示してはいけない．
+extern "x86-interrupt" fn int_handler_nmi(_: *const InterruptFrame) {
+    NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 受け取った NMI の累計
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+/// 前回の呼び出しから NMI を受け取っていれば，その時点の累計を返す
+pub fn take_new_nmis() -> Option<u64> {
+    let count = nmi_count();
+    if NMI_REPORTED.swap(count, Ordering::Relaxed) == count {
+        None
+    } else {
+        Some(count)
+    }
+}
+
+fault_handler!(int_handler_de, 0);
+fault_handler!(int_handler_db, 1);
+fault_handler!(int_handler_bp, 3);
+fault_handler!(int_handler_of, 4);
+fault_handler!(int_handler_br, 5);
+fault_handler!(int_handler_ud, 6);
+fault_handler!(int_handler_nm, 7);
+fault_handler!(int_handler_df, 8, error_code);
+fault_handler!(int_handler_cso, 9);
+fault_handler!(int_handler_ts, 10, error_code);
+fault_handler!(int_handler_np, 11, error_code);
+fault_handler!(int_handler_ss, 12, error_code);
+fault_handler!(int_handler_gp, 13, error_code);
+fault_handler!(int_handler_pf, 14, error_code);
+fault_handler!(int_handler_reserved15, 15);
+fault_handler!(int_handler_mf, 16);
+fault_handler!(int_handler_ac, 17, error_code);
+fault_handler!(int_handler_mc, 18);
+fault_handler!(int_handler_xm, 19);
+fault_handler!(int_handler_ve, 20);
+fault_handler!(int_handler_cp, 21, error_code);
+fault_handler!(int_handler_reserved22, 22);
+fault_handler!(int_handler_reserved23, 23);
+fault_handler!(int_handler_reserved24, 24);
+fault_handler!(int_handler_reserved25, 25);
+fault_handler!(int_handler_reserved26, 26);
+fault_handler!(int_handler_reserved27, 27);
+fault_handler!(int_handler_hv, 28);
+fault_handler!(int_handler_vc, 29, error_code);
+fault_handler!(int_handler_sx, 30, error_code);
+fault_handler!(int_handler_reserved31, 31);
+
+/// CPU 例外ハンドラのアドレス．添字がベクタ番号に対応する．
+fn exception_handlers() -> [u64; NUM_EXCEPTIONS] {
+    [
+        int_handler_de as u64,
+        int_handler_db as u64,
+        int_handler_nmi as u64,
+        int_handler_bp as u64,
+        int_handler_of as u64,
+        int_handler_br as u64,
+        int_handler_ud as u64,
+        int_handler_nm as u64,
+        int_handler_df as u64,
+        int_handler_cso as u64,
+        int_handler_ts as u64,
+        int_handler_np as u64,
+        int_handler_ss as u64,
+        int_handler_gp as u64,
+        int_handler_pf as u64,
+        int_handler_reserved15 as u64,
+        int_handler_mf as u64,
+        int_handler_ac as u64,
+        int_handler_mc as u64,
+        int_handler_xm as u64,
+        int_handler_ve as u64,
+        int_handler_cp as u64,
+        int_handler_reserved22 as u64,
+        int_handler_reserved23 as u64,
+        int_handler_reserved24 as u64,
+        int_handler_reserved25 as u64,
+        int_handler_reserved26 as u64,
+        int_handler_reserved27 as u64,
+        int_handler_hv as u64,
+        int_handler_vc as u64,
+        int_handler_sx as u64,
+        int_handler_reserved31 as u64,
+    ]
+}
+
+/// すべての CPU 例外にハンドラを登録する
+///
+/// この時点ではすべての例外を現在のスタックで処理する．
+pub fn setup_exception_handlers() {
+    let idt = idt();
+    for (vector, handler) in exception_handlers().iter().enumerate() {
+        set_idt_entry(
+            &mut idt[vector],
+            make_idt_attr(DescriptorType::InterruptGate, 0, true, 0),
+            *handler,
+            segment::KERNEL_CS,
+        );
+    }
+    load_idt();
+}
+
+/// NMI とダブルフォルトを専用スタック（IST）で処理するように設定する
+///
+/// segment::initialize_tss() により IST が設定された後に呼び出すこと．
+pub fn setup_ist_handlers() {
+    let idt = idt();
+    idt[vector::Number::NMI as usize].attr =
+        make_idt_attr(DescriptorType::InterruptGate, 0, true, segment::IST_FOR_NMI);
+    idt[vector::Number::DoubleFault as usize].attr = make_idt_attr(
+        DescriptorType::InterruptGate,
+        0,
+        true,
+        segment::IST_FOR_DOUBLE_FAULT,
+    );
+    load_idt();
+}
+
+/// 割り込み処理の終了を通知する．EOI の前に，処理中のベクタの配送回数を数える．
+///
+/// * `vector` - 呼び出したハンドラが処理しているベクタ番号
+pub fn notify_end_of_interrupt(vector: u8) {
+    VECTOR_COUNTERS[vector as usize]
+        .deliveries
+        .fetch_add(1, Ordering::Relaxed);
+    local_apic::end_of_interrupt();
+}
+
+/// ベクタごとの割り込みの統計
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VectorStat {
+    /// EOI を送った（正しく配送された）回数
+    pub deliveries: u64,
+    /// スプリアス割り込みとして捨てた回数
+    pub spurious: u64,
+    /// タスクのメッセージキューが溢れてメッセージを捨てた回数
+    pub queue_full_drops: u64,
+}
+
+/// ベクタごとの割り込みの統計のカウンタ
+///
+/// 割り込みハンドラが数え，タスクが読み出すのでアトミックにしておく．
+struct VectorCounters {
+    deliveries: AtomicU64,
+    spurious: AtomicU64,
+    queue_full_drops: AtomicU64,
+}
+
+impl VectorCounters {
+    const NEW: Self = VectorCounters {
+        deliveries: AtomicU64::new(0),
+        spurious: AtomicU64::new(0),
+        queue_full_drops: AtomicU64::new(0),
+    };
+
+    fn reset(&self) {
+        self.deliveries.store(0, Ordering::Relaxed);
+        self.spurious.store(0, Ordering::Relaxed);
+        self.queue_full_drops.store(0, Ordering::Relaxed);
+    }
+}
+
+static VECTOR_COUNTERS: [VectorCounters; 256] = [VectorCounters::NEW; 256];
+static VECTOR_NAMES: SpinLock<[Option<&'static str>; 256]> = SpinLock::new([None; 256]);
+
+/// ベクタに名前を付ける．名前の付いたベクタが統計の表示対象になる．
+pub fn set_vector_name(vector: u8, name: &'static str) {
+    VECTOR_NAMES.lock()[vector as usize] = Some(name);
+}
+
+pub fn vector_stat(vector: u8) -> VectorStat {
+    let counters = &VECTOR_COUNTERS[vector as usize];
+    VectorStat {
+        deliveries: counters.deliveries.load(Ordering::Relaxed),
+        spurious: counters.spurious.load(Ordering::Relaxed),
+        queue_full_drops: counters.queue_full_drops.load(Ordering::Relaxed),
+    }
+}
+
+/// スプリアス割り込みを数える．EOI を送らないハンドラから呼ぶ．
+pub fn record_spurious(vector: u8) {
+    VECTOR_COUNTERS[vector as usize]
+        .spurious
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// タスクのメッセージキューが溢れてメッセージを捨てたことを，処理中のベクタに記録する
+///
+/// * `vector` - 呼び出したハンドラが処理しているベクタ番号
+pub fn record_queue_full(vector: u8) {
+    VECTOR_COUNTERS[vector as usize]
+        .queue_full_drops
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// 名前の付いたベクタの統計を /proc/interrupts のような表で表示する
+pub fn print_stats() {
+    printk!(
+        "{:>6} {:>12} {:>10} {:>10}  {}\n",
+        "vector",
+        "deliveries",
+        "spurious",
+        "dropped",
+        "name"
+    );
+    // 表示の間コンソールのロックと一緒に持たないよう，名前は先に写しておく
+    let names = *VECTOR_NAMES.lock();
+    for vector in 0..=255u8 {
+        let name = match names[vector as usize] {
+            Some(name) => name,
+            None => continue,
+        };
+        let stat = vector_stat(vector);
+        printk!(
+            "{:>#6x} {:>12} {:>10} {:>10}  {}\n",
+            vector,
+            stat.deliveries,
+            stat.spurious,
+            stat.queue_full_drops,
+            name
+        );
+    }
+    printk!("NMI: {}\n", nmi_count());
+}
+
+/// 割り込みを禁止し，禁止する前に割り込みが許可されていたかを返す
+pub fn disable_interrupts() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+    }
+    // RFLAGS.IF
+    rflags.get_bit(9)
+}
+
+/// disable_interrupts() が返した状態に戻す
+pub fn restore_interrupts(interrupts_enabled: bool) {
+    if interrupts_enabled {
+        unsafe {
+            asm!("sti");
+        }
+    }
+}
+
+/// 割り込みを禁止した状態で f を実行し，終了後に元の割り込み許可状態に戻す
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let interrupts_enabled = disable_interrupts();
+    let ret = f();
+    restore_interrupts(interrupts_enabled);
+    ret
+}
+
+/// 動的に割り当てるベクタの範囲．固定のベクタ（vector::Number）とは重ならない．
+const DYNAMIC_VECTOR_BEGIN: usize = 0x50;
+const DYNAMIC_VECTOR_END: usize = 0xf0;
+const NUM_DYNAMIC_VECTORS: usize = DYNAMIC_VECTOR_END - DYNAMIC_VECTOR_BEGIN;
+
+/// 割り込みハンドラの型
+pub type Handler = extern "x86-interrupt" fn(*const InterruptFrame);
+
+/// ディスパッチ経由で呼び出す関数の型．引数には登録時の data が渡される．
+pub type DispatchFn = fn(data: usize);
+
+/// 動的に割り当てた割り込みベクタ
+///
+/// MSI の設定などにはベクタ番号を渡し，使い終わったら release_vector() で返却する．
+#[derive(Debug, PartialEq, Eq)]
+pub struct InterruptVector(u8);
+
+impl InterruptVector {
+    pub fn number(&self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for InterruptVector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct DispatchEntry {
+    f: DispatchFn,
+    data: usize,
+}
+
+static VECTOR_ALLOCATED: SpinLock<[bool; NUM_DYNAMIC_VECTORS]> =
+    SpinLock::new([false; NUM_DYNAMIC_VECTORS]);
+static DISPATCH_TABLE: SpinLock<[Option<DispatchEntry>; NUM_DYNAMIC_VECTORS]> =
+    SpinLock::new([None; NUM_DYNAMIC_VECTORS]);
+
+/// 登録された関数を呼び出し，EOI を送る
+extern "x86-interrupt" fn dispatch<const VECTOR: usize>(_: *const InterruptFrame) {
+    // 呼び出した関数が登録や解除をしても良いよう，ロックは写す間だけ保持する
+    let entry = DISPATCH_TABLE.lock()[VECTOR - DYNAMIC_VECTOR_BEGIN];
+    if let Some(entry) = entry {
+        (entry.f)(entry.data);
+    }
+    notify_end_of_interrupt(VECTOR as u8);
+}
+
+macro_rules! dispatchers {
+    ($($row:literal),*) => {
+        [$(
+            dispatch::<{ $row * 16 + 0x0 }> as u64,
+            dispatch::<{ $row * 16 + 0x1 }> as u64,
+            dispatch::<{ $row * 16 + 0x2 }> as u64,
+            dispatch::<{ $row * 16 + 0x3 }> as u64,
+            dispatch::<{ $row * 16 + 0x4 }> as u64,
+            dispatch::<{ $row * 16 + 0x5 }> as u64,
+            dispatch::<{ $row * 16 + 0x6 }> as u64,
+            dispatch::<{ $row * 16 + 0x7 }> as u64,
+            dispatch::<{ $row * 16 + 0x8 }> as u64,
+            dispatch::<{ $row * 16 + 0x9 }> as u64,
+            dispatch::<{ $row * 16 + 0xa }> as u64,
+            dispatch::<{ $row * 16 + 0xb }> as u64,
+            dispatch::<{ $row * 16 + 0xc }> as u64,
+            dispatch::<{ $row * 16 + 0xd }> as u64,
+            dispatch::<{ $row * 16 + 0xe }> as u64,
+            dispatch::<{ $row * 16 + 0xf }> as u64,
+        )*]
+    };
+}
+
+/// ディスパッチ用ハンドラのアドレス．添字は DYNAMIC_VECTOR_BEGIN からの位置．
+fn dispatchers() -> [u64; NUM_DYNAMIC_VECTORS] {
+    dispatchers!(0x5, 0x6, 0x7, 0x8, 0x9, 0xa, 0xb, 0xc, 0xd, 0xe)
+}
+
+/// 空いているベクタを 1 つ確保する．IDT にはまだ何も登録しない．
+pub fn allocate_vector() -> Result<InterruptVector, Error> {
+    let mut allocated = VECTOR_ALLOCATED.lock();
+    let index = allocated
+        .iter()
+        .position(|&allocated| !allocated)
+        .ok_or(make_error!(Code::Full))?;
+    allocated[index] = true;
+    Ok(InterruptVector((DYNAMIC_VECTOR_BEGIN + index) as u8))
+}
+
+/// 連続した count 個のベクタを確保する．先頭のベクタ番号は count の倍数になる．
+///
+/// 複数メッセージの MSI ではデバイスがデータの下位ビットを書き換えてベクタを選ぶため，
+/// 先頭をベクタ数に揃える必要がある．
+///
+/// * `count` - 確保するベクタ数（2 のべき乗）
+pub fn allocate_vector_block(count: usize) -> Result<Vec<InterruptVector>, Error> {
+    if !count.is_power_of_two() || count > NUM_DYNAMIC_VECTORS {
+        return Err(make_error!(Code::IndexOutOfRange));
+    }
+    let mut allocated = VECTOR_ALLOCATED.lock();
+    let first = (DYNAMIC_VECTOR_BEGIN..=DYNAMIC_VECTOR_END - count)
+        .step_by(count)
+        .map(|vector| vector - DYNAMIC_VECTOR_BEGIN)
+        .find(|&index| !allocated[index..index + count].contains(&true))
+        .ok_or(make_error!(Code::Full))?;
+    for allocated in &mut allocated[first..first + count] {
+        *allocated = true;
+    }
+    Ok((first..first + count)
+        .map(|index| InterruptVector((DYNAMIC_VECTOR_BEGIN + index) as u8))
+        .collect())
+}
+
+/// IDT はロード済みなので，エントリを書き換えれば LoadIDT し直さなくても反映される
+fn install(vector: &InterruptVector, handler: u64) {
+    set_idt_entry(
+        &mut idt()[vector.number() as usize],
+        make_idt_attr(DescriptorType::InterruptGate, 0, true, 0),
+        handler,
+        segment::KERNEL_CS,
+    );
+}
+
+/// 確保済みのベクタに割り込みハンドラを登録する．EOI はハンドラが送ること．
+///
+/// * `name` - 統計の表示に使う名前
+pub fn install_handler(vector: &InterruptVector, handler: Handler, name: &'static str) {
+    set_vector_name(vector.number(), name);
+    install(vector, handler as u64);
+}
+
+/// 確保済みのベクタに，割り込み発生時に f(data) を呼び出すよう登録する．EOI は自動で送られる．
+pub fn install_dispatch(vector: &InterruptVector, f: DispatchFn, data: usize, name: &'static str) {
+    let index = vector.number() as usize - DYNAMIC_VECTOR_BEGIN;
+    DISPATCH_TABLE.lock()[index] = Some(DispatchEntry { f, data });
+    set_vector_name(vector.number(), name);
+    install(vector, dispatchers()[index]);
+}
+
+/// ベクタを確保し，割り込みハンドラを登録する
+pub fn register_handler(handler: Handler, name: &'static str) -> Result<InterruptVector, Error> {
+    let vector = allocate_vector()?;
+    install_handler(&vector, handler, name);
+    Ok(vector)
+}
+
+/// ベクタを確保し，割り込み発生時に f(data) を呼び出すよう登録する
+pub fn register_dispatch(
+    f: DispatchFn,
+    data: usize,
+    name: &'static str,
+) -> Result<InterruptVector, Error> {
+    let vector = allocate_vector()?;
+    install_dispatch(&vector, f, data, name);
+    Ok(vector)
+}
+
+/// ベクタの登録を解除して返却する．デバイスの割り込みを止めてから呼び出すこと．
+pub fn release_vector(vector: InterruptVector) {
+    let index = vector.number() as usize - DYNAMIC_VECTOR_BEGIN;
+    without_interrupts(|| {
+        idt()[vector.number() as usize] = InterruptDescriptor::default();
+        DISPATCH_TABLE.lock()[index] = None;
+        VECTOR_NAMES.lock()[vector.number() as usize] = None;
+        VECTOR_COUNTERS[vector.number() as usize].reset();
+        VECTOR_ALLOCATED.lock()[index] = false;
+    });
+}
@@ -0,0 +1,304 @@
+//! I/O APIC 制御のプログラムを集めたファイル．
+//!
+//! ACPI の MADT から I/O APIC の位置と ISA IRQ の割り当て（Interrupt Source Override）を読み取り，
+//! GSI（Global System Interrupt）ごとにリダイレクションエントリを設定する．
+#![allow(dead_code)]
+
+use crate::acpi::{Madt, MadtEntry};
+use crate::error::{Code, Error};
+use crate::interrupt;
+use crate::make_error;
+use crate::paging;
+use crate::sync::OnceCell;
+use arrayvec::ArrayVec;
+use bit_field::BitField;
+use core::fmt;
+use modular_bitfield::prelude::*;
+
+/// I/O APIC の間接アクセス用レジスタ（MMIO オフセット）
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+/// I/O APIC の内部レジスタ
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+/// リダイレクションテーブルの先頭．エントリ n は 0x10 + 2n（下位）と 0x11 + 2n（上位）．
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const MAX_IO_APICS: usize = 8;
+const MAX_OVERRIDES: usize = 16;
+
+/// 割り込み信号の極性
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// 割り込み信号のトリガモード
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// リダイレクションテーブルの 1 エントリ
+#[bitfield]
+#[derive(Clone, Copy, Debug)]
+pub struct RedirectionEntry {
+    pub vector: B8,
+    pub delivery_mode: B3,
+    /// 0: 物理モード（destination は APIC ID），1: 論理モード
+    pub logical_destination: bool,
+    #[skip(setters)]
+    pub delivery_status: bool,
+    pub polarity_low: bool,
+    #[skip(setters)]
+    pub remote_irr: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    #[skip]
+    __: B39,
+    pub destination: B8,
+}
+
+/// I/O APIC 1 つ分の情報
+#[derive(Debug, Copy, Clone)]
+pub struct IoApic {
+    pub id: u8,
+    /// MMIO 領域の先頭アドレス
+    base: u64,
+    /// 最初のリダイレクションエントリに対応する GSI
+    pub gsi_base: u32,
+    /// リダイレクションエントリの数
+    pub num_entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        interrupt::without_interrupts(|| unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+        })
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        interrupt::without_interrupts(|| unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        })
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.num_entries
+    }
+
+    fn read_entry(&self, index: u32) -> RedirectionEntry {
+        let reg = REG_REDIRECTION_TABLE + 2 * index;
+        let value = self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32;
+        RedirectionEntry::from_bytes(value.to_le_bytes())
+    }
+
+    fn write_entry(&self, index: u32, entry: RedirectionEntry) {
+        let reg = REG_REDIRECTION_TABLE + 2 * index;
+        let value = u64::from_le_bytes(entry.into_bytes());
+        // 設定途中の割り込みを防ぐため，先にマスクしてから上位，下位の順に書き込む
+        self.write(reg, *self.read(reg).set_bit(16, true));
+        self.write(reg + 1, value.get_bits(32..=63) as u32);
+        self.write(reg, value.get_bits(0..=31) as u32);
+    }
+}
+
+impl fmt::Display for IoApic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "I/O APIC {} at {:08x}, GSI {}-{}",
+            self.id,
+            self.base,
+            self.gsi_base,
+            self.gsi_base + self.num_entries - 1
+        )
+    }
+}
+
+/// ISA IRQ と GSI の対応
+#[derive(Debug, Copy, Clone)]
+pub struct IsaIrqRoute {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// MADT の Interrupt Source Override
+#[derive(Debug, Copy, Clone)]
+struct InterruptSourceOverride {
+    irq: u8,
+    route: IsaIrqRoute,
+}
+
+static IO_APICS: OnceCell<ArrayVec<IoApic, MAX_IO_APICS>> = OnceCell::new();
+static OVERRIDES: OnceCell<ArrayVec<InterruptSourceOverride, MAX_OVERRIDES>> = OnceCell::new();
+
+/// 初期化済みの I/O APIC を返す．initialize() の前は空．
+pub fn io_apics() -> &'static [IoApic] {
+    IO_APICS.get().map_or(&[], |io_apics| io_apics.as_slice())
+}
+
+/// MADT から I/O APIC を探して初期化する．すべてのリダイレクションエントリはマスクされる．
+///
+/// acpi::initialize() と paging::activate() の後に呼び出すこと．
+pub fn initialize() -> Result<(), Error> {
+    let madt = Madt::find()?;
+    let mut io_apics = ArrayVec::<IoApic, MAX_IO_APICS>::new();
+    let mut overrides = ArrayVec::<InterruptSourceOverride, MAX_OVERRIDES>::new();
+    for entry in madt.entries() {
+        match entry {
+            MadtEntry::IoApic {
+                id,
+                address,
+                gsi_base,
+            } => {
+                let base = paging::map_mmio(address as u64, paging::PAGE_SIZE_4K)?;
+                let mut io_apic = IoApic {
+                    id,
+                    base,
+                    gsi_base,
+                    num_entries: 0,
+                };
+                // VERSION レジスタの 23:16 は最大のエントリ番号
+                io_apic.num_entries = io_apic.read(REG_VERSION).get_bits(16..=23) + 1;
+                for index in 0..io_apic.num_entries {
+                    io_apic.write_entry(index, RedirectionEntry::new().with_masked(true));
+                }
+                io_apics
+                    .try_push(io_apic)
+                    .map_err(|_| make_error!(Code::Full))?;
+            }
+            MadtEntry::InterruptSourceOverride {
+                bus: 0, // ISA
+                source,
+                gsi,
+                flags,
+            } => {
+                let iso = InterruptSourceOverride {
+                    irq: source,
+                    route: IsaIrqRoute {
+                        gsi,
+                        // 00b はバスの規定に従う．ISA はアクティブハイ，エッジトリガ．
+                        polarity: match flags.get_bits(0..=1) {
+                            0b11 => Polarity::ActiveLow,
+                            _ => Polarity::ActiveHigh,
+                        },
+                        trigger_mode: match flags.get_bits(2..=3) {
+                            0b11 => TriggerMode::Level,
+                            _ => TriggerMode::Edge,
+                        },
+                    },
+                };
+                overrides
+                    .try_push(iso)
+                    .map_err(|_| make_error!(Code::Full))?;
+            }
+            _ => {}
+        }
+    }
+
+    if io_apics.is_empty() {
+        return Err(make_error!(Code::NotFound));
+    }
+    IO_APICS.set(io_apics)?;
+    OVERRIDES.set(overrides)?;
+    Ok(())
+}
+
+/// ISA IRQ が繋がっている GSI を返す．上書きがなければ IRQ 番号と同じ GSI に繋がっている．
+pub fn isa_irq_route(irq: u8) -> IsaIrqRoute {
+    OVERRIDES
+        .get()
+        .into_iter()
+        .flatten()
+        .find(|iso| iso.irq == irq)
+        .map(|iso| iso.route)
+        .unwrap_or(IsaIrqRoute {
+            gsi: irq as u32,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        })
+}
+
+/// GSI を担当する I/O APIC とエントリ番号を返す
+fn find_entry(gsi: u32) -> Result<(&'static IoApic, u32), Error> {
+    io_apics()
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .map(|io_apic| (io_apic, gsi - io_apic.gsi_base))
+        .ok_or(make_error!(Code::IndexOutOfRange))
+}
+
+pub fn read_redirection(gsi: u32) -> Result<RedirectionEntry, Error> {
+    let (io_apic, index) = find_entry(gsi)?;
+    Ok(io_apic.read_entry(index))
+}
+
+pub fn write_redirection(gsi: u32, entry: RedirectionEntry) -> Result<(), Error> {
+    let (io_apic, index) = find_entry(gsi)?;
+    io_apic.write_entry(index, entry);
+    Ok(())
+}
+
+/// GSI の割り込みを指定した Local APIC のベクタに配送するよう設定し，マスクを解除する
+///
+/// * `gsi` - 設定する GSI
+/// * `vector` - 割り込みベクタ番号
+/// * `destination` - 配送先の Local APIC ID
+/// * `polarity` - 信号の極性
+/// * `trigger_mode` - トリガモード
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    destination: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+) -> Result<(), Error> {
+    let entry = RedirectionEntry::new()
+        .with_vector(vector)
+        .with_delivery_mode(0) // Fixed
+        .with_logical_destination(false)
+        .with_polarity_low(polarity == Polarity::ActiveLow)
+        .with_level_triggered(trigger_mode == TriggerMode::Level)
+        .with_masked(false)
+        .with_destination(destination);
+    write_redirection(gsi, entry)
+}
+
+/// ISA IRQ（キーボードなら 1，RTC なら 8 など）を Interrupt Source Override に従って設定し，
+/// 設定した GSI を返す
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u8) -> Result<u32, Error> {
+    let route = isa_irq_route(irq);
+    route_gsi(
+        route.gsi,
+        vector,
+        destination,
+        route.polarity,
+        route.trigger_mode,
+    )?;
+    Ok(route.gsi)
+}
+
+/// GSI の割り込みをマスクする
+pub fn mask(gsi: u32) -> Result<(), Error> {
+    set_masked(gsi, true)
+}
+
+/// GSI の割り込みのマスクを解除する
+pub fn unmask(gsi: u32) -> Result<(), Error> {
+    set_masked(gsi, false)
+}
+
+fn set_masked(gsi: u32, masked: bool) -> Result<(), Error> {
+    let (io_apic, index) = find_entry(gsi)?;
+    let reg = REG_REDIRECTION_TABLE + 2 * index;
+    io_apic.write(reg, *io_apic.read(reg).set_bit(16, masked));
+    Ok(())
+}
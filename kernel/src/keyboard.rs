@@ -0,0 +1,78 @@
+//! キーボード入力を扱うプログラムを集めたファイル．
+//!
+//! USB HID のブートプロトコルのキーコード（Usage ID）を US 配列の文字に変換する．
+#![allow(dead_code)]
+
+use core::fmt;
+
+/// 修飾キーの押下状態（ブートプロトコルのレポートの 0 バイト目）
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Modifier(pub u8);
+
+impl Modifier {
+    pub const LEFT_CONTROL: u8 = 1 << 0;
+    pub const LEFT_SHIFT: u8 = 1 << 1;
+    pub const LEFT_ALT: u8 = 1 << 2;
+    pub const LEFT_GUI: u8 = 1 << 3;
+    pub const RIGHT_CONTROL: u8 = 1 << 4;
+    pub const RIGHT_SHIFT: u8 = 1 << 5;
+    pub const RIGHT_ALT: u8 = 1 << 6;
+    pub const RIGHT_GUI: u8 = 1 << 7;
+
+    pub fn shift(&self) -> bool {
+        self.0 & (Self::LEFT_SHIFT | Self::RIGHT_SHIFT) != 0
+    }
+
+    pub fn control(&self) -> bool {
+        self.0 & (Self::LEFT_CONTROL | Self::RIGHT_CONTROL) != 0
+    }
+
+    pub fn alt(&self) -> bool {
+        self.0 & (Self::LEFT_ALT | Self::RIGHT_ALT) != 0
+    }
+}
+
+impl fmt::Display for Modifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}", self.0)
+    }
+}
+
+/// F12 キーのキーコード
+pub const KEY_F12: u8 = 0x45;
+
+/// 1〜0 のキー（0x1e–0x27）
+const DIGITS: &[u8] = b"1234567890";
+const DIGITS_SHIFTED: &[u8] = b"!@#$%^&*()";
+/// - から / までの記号のキー（0x2d–0x38）
+const SYMBOLS: &[u8] = b"-=[]\\#;'`,./";
+const SYMBOLS_SHIFTED: &[u8] = b"_+{}|~:\"~<>?";
+/// テンキーの / から . まで（0x54–0x63）
+const KEYPAD: &[u8] = b"/*-+\n1234567890.";
+
+/// キーコードを文字に変換する．コンソールに表示できる文字と改行以外は None を返す．
+///
+/// * `keycode` - HID のキーコード
+/// * `modifier` - 修飾キーの押下状態
+pub fn keycode_to_ascii(keycode: u8, modifier: Modifier) -> Option<char> {
+    let shift = modifier.shift();
+    let c = match keycode {
+        0x04..=0x1d => {
+            let c = b'a' + (keycode - 0x04);
+            if shift {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        }
+        0x1e..=0x27 if shift => DIGITS_SHIFTED[(keycode - 0x1e) as usize],
+        0x1e..=0x27 => DIGITS[(keycode - 0x1e) as usize],
+        0x28 => b'\n',
+        0x2c => b' ',
+        0x2d..=0x38 if shift => SYMBOLS_SHIFTED[(keycode - 0x2d) as usize],
+        0x2d..=0x38 => SYMBOLS[(keycode - 0x2d) as usize],
+        0x54..=0x63 => KEYPAD[(keycode - 0x54) as usize],
+        _ => return None,
+    };
+    Some(c as char)
+}
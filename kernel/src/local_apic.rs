@@ -0,0 +1,294 @@
+//! Local APIC 制御のプログラムを集めたファイル．
+//!
+//! IA32_APIC_BASE MSR から Local APIC のベースアドレスを求める．
+//! CPU が対応していれば x2APIC モードに切り替え，レジスタには MSR 経由でアクセスする．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::error::Error;
+use crate::interrupt;
+use crate::paging;
+use crate::segment;
+use crate::sync::OnceCell;
+use bit_field::BitField;
+use core::arch::x86_64::__cpuid;
+use modular_bitfield::prelude::*;
+
+/// IA32_APIC_BASE MSR
+const IA32_APIC_BASE: u32 = 0x1b;
+/// x2APIC モードのレジスタに対応する MSR の先頭
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC のレジスタ（xAPIC モードでの MMIO オフセット）
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Register {
+    Id = 0x020,
+    Version = 0x030,
+    TaskPriority = 0x080,
+    EndOfInterrupt = 0x0b0,
+    SpuriousInterruptVector = 0x0f0,
+    /// In-Service Register．ベクタ 0–31 から 224–255 まで 32 個ずつ 8 つのレジスタに分かれる．
+    InService0 = 0x100,
+    ErrorStatus = 0x280,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    LvtThermalSensor = 0x330,
+    LvtPerformanceCounter = 0x340,
+    LvtLint0 = 0x350,
+    LvtLint1 = 0x360,
+    LvtError = 0x370,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivideConfiguration = 0x3e0,
+}
+
+/// Local Vector Table の種類
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Lvt {
+    Timer,
+    ThermalSensor,
+    PerformanceCounter,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+impl Lvt {
+    fn register(&self) -> Register {
+        match self {
+            Lvt::Timer => Register::LvtTimer,
+            Lvt::ThermalSensor => Register::LvtThermalSensor,
+            Lvt::PerformanceCounter => Register::LvtPerformanceCounter,
+            Lvt::Lint0 => Register::LvtLint0,
+            Lvt::Lint1 => Register::LvtLint1,
+            Lvt::Error => Register::LvtError,
+        }
+    }
+}
+
+/// LVT の 1 エントリ
+#[bitfield]
+#[derive(Clone, Copy, Debug)]
+pub struct LvtEntry {
+    pub vector: B8,
+    pub delivery_mode: B3,
+    #[skip]
+    __: B1,
+    #[skip(setters)]
+    pub delivery_status: bool,
+    pub polarity_low: bool,
+    #[skip(setters)]
+    pub remote_irr: bool,
+    pub level_triggered: bool,
+    pub masked: bool,
+    /// タイマ LVT のみ．0: ワンショット，1: 周期，2: TSC デッドライン
+    pub timer_mode: B2,
+    #[skip]
+    __: B13,
+}
+
+/// Local APIC へのアクセス方法
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mode {
+    /// 未初期化
+    Disabled,
+    /// MMIO でアクセスする．値はベースアドレス．
+    XApic(u64),
+    /// MSR でアクセスする
+    X2Apic,
+}
+
+static MODE: OnceCell<Mode> = OnceCell::new();
+
+pub fn mode() -> Mode {
+    MODE.get().copied().unwrap_or(Mode::Disabled)
+}
+
+/// CPU が x2APIC に対応していれば真を返す
+pub fn supports_x2apic() -> bool {
+    // CPUID.01H:ECX[21]
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.ecx.get_bit(21)
+}
+
+/// Local APIC を初期化する
+///
+/// x2APIC に対応していれば x2APIC モードに切り替え，
+/// そうでなければ MMIO 領域をキャッシュ無効で写像する．
+/// 最後にスプリアス割り込みのベクタを設定して Local APIC を有効にする．
+pub fn initialize() -> Result<(), Error> {
+    let mut apic_base = unsafe { asm::ReadMSR(IA32_APIC_BASE) };
+    let base_addr = apic_base.get_bits(12..=51) << 12;
+
+    let mode = if supports_x2apic() {
+        // EN と EXTD を立てて x2APIC モードに移行する
+        apic_base.set_bit(11, true).set_bit(10, true);
+        unsafe {
+            asm::WriteMSR(IA32_APIC_BASE, apic_base);
+        }
+        Mode::X2Apic
+    } else {
+        paging::map_mmio(base_addr, paging::PAGE_SIZE_4K)?;
+        Mode::XApic(base_addr)
+    };
+    MODE.set(mode)?;
+
+    let idt = interrupt::idt();
+    interrupt::set_idt_entry(
+        &mut idt[interrupt::vector::Number::LAPICSpurious as usize],
+        interrupt::make_idt_attr(interrupt::DescriptorType::InterruptGate, 0, true, 0),
+        int_handler_spurious as u64,
+        segment::KERNEL_CS,
+    );
+    interrupt::load_idt();
+    interrupt::set_vector_name(
+        interrupt::vector::Number::LAPICSpurious as u8,
+        "lapic spurious",
+    );
+
+    set_spurious_vector(interrupt::vector::Number::LAPICSpurious as u8, true);
+    Ok(())
+}
+
+/// スプリアス割り込みには EOI を送ってはいけない
+extern "x86-interrupt" fn int_handler_spurious(_: *const interrupt::InterruptFrame) {
+    interrupt::record_spurious(interrupt::vector::Number::LAPICSpurious as u8);
+}
+
+/// xAPIC モードでの MMIO オフセットに対応する x2APIC モードの MSR を返す
+///
+/// x2APIC モードの ICR は 64 ビットの 1 つの MSR なので，上位に当たる MSR は存在しない．
+/// アクセスすると #GP になるため None を返す．
+fn x2apic_msr(offset: u32) -> Option<u32> {
+    if offset == Register::InterruptCommandHigh as u32 {
+        None
+    } else {
+        Some(X2APIC_MSR_BASE + (offset >> 4))
+    }
+}
+
+/// レジスタを読み取る
+///
+/// x2APIC モードでは InterruptCommandHigh は存在しないので 0 を返す．ICR は send_ipi() で扱うこと．
+pub fn read(reg: Register) -> u32 {
+    read_offset(reg as u32)
+}
+
+/// xAPIC モードでの MMIO オフセットを指定してレジスタを読み取る
+fn read_offset(offset: u32) -> u32 {
+    match mode() {
+        Mode::XApic(base) => unsafe {
+            core::ptr::read_volatile((base + offset as u64) as *const u32)
+        },
+        Mode::X2Apic => match x2apic_msr(offset) {
+            Some(msr) => unsafe { asm::ReadMSR(msr) as u32 },
+            None => 0,
+        },
+        Mode::Disabled => 0,
+    }
+}
+
+/// レジスタに書き込む
+///
+/// x2APIC モードでは InterruptCommandHigh は存在しないので何もしない．ICR は send_ipi() で扱うこと．
+pub fn write(reg: Register, value: u32) {
+    match mode() {
+        Mode::XApic(base) => unsafe {
+            core::ptr::write_volatile((base + reg as u64) as *mut u32, value)
+        },
+        Mode::X2Apic => {
+            if let Some(msr) = x2apic_msr(reg as u32) {
+                unsafe { asm::WriteMSR(msr, value as u64) }
+            }
+        }
+        Mode::Disabled => {}
+    }
+}
+
+/// この CPU の Local APIC ID を返す
+pub fn id() -> u32 {
+    let id = read(Register::Id);
+    match mode() {
+        Mode::X2Apic => id,
+        _ => id.get_bits(24..=31),
+    }
+}
+
+/// バージョンレジスタを返す．下位 8 ビットがバージョン，23:16 が最大 LVT エントリ番号．
+pub fn version() -> u32 {
+    read(Register::Version)
+}
+
+pub fn task_priority() -> u8 {
+    read(Register::TaskPriority).get_bits(0..=7) as u8
+}
+
+pub fn set_task_priority(priority: u8) {
+    write(Register::TaskPriority, priority as u32);
+}
+
+/// 割り込み処理の終了を通知する
+pub fn end_of_interrupt() {
+    write(Register::EndOfInterrupt, 0);
+}
+
+/// 処理中の割り込みのうち，最も優先度の高いベクタ番号を返す
+///
+/// 割り込みハンドラの中で呼べば，そのハンドラが処理しているベクタが分かる．
+/// EOI はこのベクタの In-Service ビットを下ろす．
+pub fn in_service_vector() -> Option<u8> {
+    (0..8u32).rev().find_map(|i| {
+        let isr = read_offset(Register::InService0 as u32 + i * 0x10);
+        if isr == 0 {
+            None
+        } else {
+            Some((i * 32 + 31 - isr.leading_zeros()) as u8)
+        }
+    })
+}
+
+/// スプリアス割り込みベクタレジスタを設定する
+///
+/// * `vector` - スプリアス割り込みのベクタ番号
+/// * `apic_enabled` - Local APIC をソフトウェア的に有効にするなら真
+pub fn set_spurious_vector(vector: u8, apic_enabled: bool) {
+    let mut svr = read(Register::SpuriousInterruptVector);
+    svr.set_bits(0..=7, vector as u32).set_bit(8, apic_enabled);
+    write(Register::SpuriousInterruptVector, svr);
+}
+
+pub fn read_lvt(lvt: Lvt) -> LvtEntry {
+    LvtEntry::from_bytes(read(lvt.register()).to_le_bytes())
+}
+
+pub fn write_lvt(lvt: Lvt, entry: LvtEntry) {
+    write(lvt.register(), u32::from_le_bytes(entry.into_bytes()));
+}
+
+/// プロセッサ間割り込みを送る
+///
+/// * `destination` - 宛先の Local APIC ID
+/// * `command` - ICR の下位 32 ビット（ベクタ番号，配送モードなど）
+pub fn send_ipi(destination: u32, command: u32) {
+    match mode() {
+        Mode::XApic(_) => {
+            let mut high = 0u32;
+            high.set_bits(24..=31, destination);
+            write(Register::InterruptCommandHigh, high);
+            // 下位への書き込みで送信される
+            write(Register::InterruptCommandLow, command);
+        }
+        Mode::X2Apic => {
+            let mut icr = command as u64;
+            icr.set_bits(32..=63, destination as u64);
+            unsafe {
+                asm::WriteMSR(
+                    X2APIC_MSR_BASE + (Register::InterruptCommandLow as u32 >> 4),
+                    icr,
+                );
+            }
+        }
+        Mode::Disabled => {}
+    }
+}
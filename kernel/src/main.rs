@@ -4,31 +4,48 @@
 #![feature(lang_items)]
 #![feature(asm)]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
+mod acpi;
 mod asm;
 mod console;
+mod dma;
 mod driver;
 mod error;
 mod font;
 mod frame_buffer_config;
 mod graphics;
 mod hankaku;
+mod heap;
 mod interrupt;
+mod ioapic;
+mod keyboard;
+mod local_apic;
 mod logger;
+mod memory_manager;
 mod memory_map;
 mod mouse;
+mod paging;
 mod pci;
+mod pic;
+mod ring_buffer;
+mod segment;
+mod sync;
+mod task;
+mod timer;
 mod utils;
 
+extern crate alloc;
 extern crate num;
 #[macro_use]
 extern crate num_derive;
 
-use arrayvec::ArrayVec;
 use bit_field::BitField;
+use core::alloc::Layout;
+use core::convert::TryFrom;
 use core::fmt;
 use core::panic::PanicInfo;
-use cty::{uint16_t, uint64_t};
+use core::sync::atomic::{AtomicU8, Ordering};
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use log::{Level, LevelFilter};
@@ -37,6 +54,7 @@ use font::*;
 use frame_buffer_config::FrameBufferConfig;
 use graphics::*;
 use logger::Logger;
+use memory_manager::*;
 use memory_map::*;
 
 #[lang = "eh_personality"]
@@ -44,12 +62,18 @@ extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(panic_info: &PanicInfo) -> ! {
+    unlock_console_for_panic();
     printk!("Kernel Panic!\n{}", panic_info);
     loop {
         hlt()
     }
 }
 
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    heap::alloc_error(layout)
+}
+
 fn hlt() {
     unsafe {
         asm!("hlt");
@@ -72,12 +96,67 @@ macro_rules! printk {
 
 fn _printk(buf: &[u8]) {
     let txt = core::str::from_utf8(buf).unwrap_or("?\n");
-    global::console().put_string(txt);
+    // コンソールの初期化前の出力は捨てる
+    if let Some(console) = global::CONSOLE.get() {
+        console.lock().put_string(txt);
+    }
+}
+
+/// コンソールのロックを強制的に解放する
+///
+/// パニックや例外の報告は，コンソールのロックを保持した処理を中断して行われることがある．
+/// そこから戻ることはないので，ロックを解放して表示できるようにする．
+pub fn unlock_console_for_panic() {
+    if let Some(console) = global::CONSOLE.get() {
+        unsafe {
+            console.force_unlock();
+        }
+    }
 }
 
-extern "C" fn mouse_observer(displacement_x: i8, displacement_y: i8) {
-    global::mouse_cursor()
-        .move_relative(&Vector2D::new(displacement_x as i32, displacement_y as i32));
+/// USB ドライバから呼ばれるコールバックは，入力をフォーカスを持つタスクに送るだけにして，
+/// 入力の処理はそのタスクで行う．キューが溢れたメッセージは捨てる．
+fn post_input(msg: Message) {
+    let _ = task::send_to_focused(msg);
+}
+
+extern "C" fn mouse_observer(buttons: u8, displacement_x: i8, displacement_y: i8) {
+    static PREV_BUTTONS: AtomicU8 = AtomicU8::new(0);
+
+    let buttons = mouse::MouseButtons(buttons);
+    let prev_buttons = mouse::MouseButtons(PREV_BUTTONS.swap(buttons.0, Ordering::Relaxed));
+    if displacement_x != 0 || displacement_y != 0 {
+        post_input(Message::MouseMove {
+            displacement: Vector2D::new(displacement_x as i32, displacement_y as i32),
+            buttons,
+        });
+    }
+    for button in buttons.changed_from(prev_buttons) {
+        post_input(Message::MouseButton {
+            button,
+            pressed: buttons.is_pressed(button),
+        });
+    }
+}
+
+extern "C" fn keyboard_observer(modifier: u8, keycode: u8, press: bool) {
+    let modifier = keyboard::Modifier(modifier);
+    post_input(if press {
+        Message::KeyPush { keycode, modifier }
+    } else {
+        Message::KeyRelease { keycode, modifier }
+    });
+}
+
+extern "C" fn port_observer(port: u8, connected: bool) {
+    let _ = task::send_message(
+        task::MAIN_TASK,
+        if connected {
+            Message::DeviceAttached { port }
+        } else {
+            Message::DeviceDetached { port }
+        },
+    );
 }
 
 fn switch_ehci_to_xhci(xhc_dev: &pci::Device) {
@@ -105,94 +184,228 @@ fn switch_ehci_to_xhci(xhc_dev: &pci::Device) {
     );
 }
 
-#[derive(Debug)]
-pub enum MessageType {
-    InterruptXHCI,
+/// タスクのメッセージキューに届くメッセージ
+#[derive(Debug, Copy, Clone)]
+pub enum Message {
+    /// ソフトウェアタイマが期限を迎えた．overruns はキューが溢れて届けられずに過ぎた周期の数
+    /// （ワンショットタイマでは常に 0）．
+    TimerTimeout {
+        id: timer::TimerID,
+        value: u64,
+        overruns: u64,
+    },
+    /// マウスが動いた．buttons は移動したときのボタンの押下状態．
+    MouseMove {
+        displacement: Vector2D<i32>,
+        buttons: mouse::MouseButtons,
+    },
+    /// マウスのボタンが押された（pressed = true）か離された
+    MouseButton {
+        button: mouse::MouseButton,
+        pressed: bool,
+    },
+    KeyPush {
+        keycode: u8,
+        modifier: keyboard::Modifier,
+    },
+    KeyRelease {
+        keycode: u8,
+        modifier: keyboard::Modifier,
+    },
+    /// USB のポートに繋がったデバイスが使えるようになった
+    DeviceAttached { port: u8 },
+    /// USB のポートからデバイスが外された
+    DeviceDetached { port: u8 },
+    /// ドライバ独自のイベント．source は送り元のドライバ名，code と data の意味はドライバが決める．
+    #[allow(dead_code)]
+    Driver {
+        source: &'static str,
+        code: u32,
+        data: u64,
+    },
 }
 
-impl fmt::Display for MessageType {
+impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
-pub struct Message {
-    msg_type: MessageType,
+/// xHC のイベントリングにイベントが届いたことを xhci_task に知らせる
+static XHCI_EVENT: sync::Event = sync::Event::new(sync::EventMode::AutoReset);
+
+fn on_xhci_interrupt(_: usize) {
+    XHCI_EVENT.set();
 }
 
-impl Message {
-    const fn new(msg_type: MessageType) -> Self {
-        Message { msg_type }
+/// xHC のイベントを処理するタスク．data は xHC のハンドル．
+///
+/// 通知は 1 回分にまとまるが，UsbReceiveEvent はイベントリングが空になるまで処理するので取りこぼさない．
+/// USB ドライバを呼び出すのはこのタスクだけにすること．
+fn xhci_task(_: task::TaskID, xhc_handle: u64) {
+    loop {
+        XHCI_EVENT.wait();
+        unsafe {
+            driver::UsbReceiveEvent(xhc_handle as driver::XhcHandle);
+        }
+        driver::print_log();
     }
 }
 
-extern "x86-interrupt" fn int_handler_xhci(_: *const interrupt::InterruptFrame) {
-    global::main_queue().push(Message::new(MessageType::InterruptXHCI));
-    interrupt::notify_end_of_interrupt();
+const TASKBAR_COLOR: PixelColor = PixelColor::new(1, 8, 17);
+
+/// タスクバーの右端に起動からの経過時間を表示する
+///
+/// 時計のタスクとマウスカーソルを動かすメインタスクが同時にフレームバッファに書かないよう，
+/// マウスカーソルのロックを取って描画する．
+fn draw_uptime(secs: u64) {
+    use core::fmt::Write;
+
+    let _mouse_cursor = global::mouse_cursor();
+    let pixel_writer = global::pixel_writer();
+    let fb_config = global::frame_buffer_config();
+    let frame_width = fb_config.horizontal_resolution() as i32;
+    let frame_height = fb_config.vertical_resolution() as i32;
+
+    let mut buf = [0u8; 16];
+    let mut wrapper = utils::fmt::Wrapper::new(&mut buf);
+    let _ = write!(
+        wrapper,
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    let txt = core::str::from_utf8(&buf)
+        .unwrap_or("?")
+        .trim_end_matches('\0');
+
+    let pos = Vector2D::new(frame_width - 8 * 8 - 10, frame_height - 33);
+    fill_rectangle(
+        pixel_writer,
+        &pos,
+        &Vector2D::new(8 * 8, 16),
+        &TASKBAR_COLOR,
+    );
+    write_string(
+        pixel_writer,
+        pos.x,
+        pos.y,
+        txt,
+        &PixelColor::new(160, 160, 160),
+    );
+}
+
+/// タスクバーの時計を更新するタスク
+fn clock_task(_: task::TaskID, _: u64) {
+    loop {
+        draw_uptime(timer::tick() / timer::TIMER_FREQ);
+        // 次の秒の変わり目まで眠る
+        task::sleep_ms(1000 - timer::uptime_ms() % 1000);
+    }
 }
 
 const DESKTOP_BG_COLOR: PixelColor = PixelColor::new(45, 118, 237);
 const DESKTOP_FG_COLOR: PixelColor = PixelColor::new(255, 255, 255);
 
 mod global {
+    use crate::sync::{OnceCell, SpinLock, SpinLockGuard};
     use crate::*;
 
-    pub(super) static mut LOGGER: Logger = Logger::new(Level::Warn);
-    pub fn logger() -> &'static mut Logger {
-        unsafe { &mut LOGGER }
+    pub static LOGGER: Logger = Logger::new(Level::Warn);
+
+    pub static FRAME_BUFFER_CONFIG: OnceCell<FrameBufferConfig> = OnceCell::new();
+    pub fn frame_buffer_config() -> &'static FrameBufferConfig {
+        FRAME_BUFFER_CONFIG.get().unwrap()
     }
 
-    pub(super) static mut PIXEL_WRITER: Option<PixelWriter> = None;
+    pub static PIXEL_WRITER: OnceCell<PixelWriter> = OnceCell::new();
     pub fn pixel_writer() -> &'static PixelWriter {
-        unsafe { PIXEL_WRITER.as_ref().unwrap() }
+        PIXEL_WRITER.get().unwrap()
     }
 
-    pub(super) static mut CONSOLE: Option<console::Console> = None;
-    pub fn console() -> &'static mut console::Console<'static> {
-        unsafe { CONSOLE.as_mut().unwrap() }
-    }
+    pub static CONSOLE: OnceCell<SpinLock<console::Console<'static>>> = OnceCell::new();
 
-    pub(super) static mut MOUSE_CURSOR: Option<mouse::MouseCursor> = None;
-    pub fn mouse_cursor() -> &'static mut mouse::MouseCursor<'static> {
-        unsafe { MOUSE_CURSOR.as_mut().unwrap() }
+    pub static MOUSE_CURSOR: OnceCell<SpinLock<mouse::MouseCursor<'static>>> = OnceCell::new();
+    pub fn mouse_cursor() -> SpinLockGuard<'static, mouse::MouseCursor<'static>> {
+        MOUSE_CURSOR.get().unwrap().lock()
     }
 
-    pub(super) static mut XHC_HANDLE: Option<driver::XhcHandle> = None;
+    pub static XHC_HANDLE: OnceCell<driver::XhcHandle> = OnceCell::new();
     pub fn xhc_handle() -> driver::XhcHandle {
-        unsafe { XHC_HANDLE.unwrap() }
+        *XHC_HANDLE.get().unwrap()
     }
 
-    pub(super) static mut MAIN_QUEUE: ArrayVec<Message, 32> = ArrayVec::<Message, 32>::new_const();
-    pub fn main_queue() -> &'static mut ArrayVec<Message, 32> {
-        unsafe { &mut MAIN_QUEUE }
+    static MEMORY_MANAGER: SpinLock<BitmapMemoryManager> =
+        SpinLock::new(BitmapMemoryManager::new());
+    pub fn memory_manager() -> SpinLockGuard<'static, BitmapMemoryManager> {
+        MEMORY_MANAGER.lock()
     }
 }
 
-#[no_mangle]
-pub extern "C" fn KernelMain(
-    fb_config: &'static FrameBufferConfig,
-    memory_map: &'static MemoryMap,
-) -> ! {
-    let pixel_writer: &PixelWriter;
-    unsafe {
-        log::set_logger(global::logger())
-            .map(|()| log::set_max_level(LevelFilter::Trace))
-            .unwrap();
+/// カーネルのメインスタックの大きさ（バイト）
+const KERNEL_MAIN_STACK_SIZE: usize = 1024 * 1024;
+
+/// カーネルのメインスタック
+///
+/// スタックの直下にガードページを置き，ページングの設定後に写像を解除する．
+/// スタックが溢れるとガードページへのアクセスでページフォルトが発生し，
+/// IST 上で動くダブルフォルトハンドラに報告される．
+#[repr(C, align(4096))]
+pub struct KernelMainStack {
+    guard_page: [u8; paging::PAGE_SIZE_4K as usize],
+    stack: [u8; KERNEL_MAIN_STACK_SIZE],
+}
 
-        global::PIXEL_WRITER = Some(PixelWriter::new(fb_config));
-        pixel_writer = global::pixel_writer();
+/// asmfunc.asm の KernelMain が UEFI ローダのスタックからこのスタックに切り替える
+#[no_mangle]
+static mut KERNEL_MAIN_STACK: KernelMainStack = KernelMainStack {
+    guard_page: [0; paging::PAGE_SIZE_4K as usize],
+    stack: [0; KERNEL_MAIN_STACK_SIZE],
+};
+
+/// KERNEL_MAIN_STACK 全体の大きさ（バイト）
+///
+/// asmfunc.asm の KernelMain は KERNEL_MAIN_STACK にこの値を足した位置をスタックの底にする．
+/// ガードページやスタックの大きさを変えても asm 側を直す必要はない．
+#[no_mangle]
+static KERNEL_MAIN_STACK_BYTES: u64 = core::mem::size_of::<KernelMainStack>() as u64;
 
-        global::CONSOLE = Some(console::Console::new(
+/// asmfunc.asm の KernelMain からカーネル用のスタック上で呼び出される
+#[no_mangle]
+pub extern "C" fn KernelMainNewStack(
+    fb_config_ref: &'static FrameBufferConfig,
+    memory_map_ref: &'static MemoryMap,
+    acpi_table: *const acpi::Rsdp,
+) -> ! {
+    // UEFI ローダから受け取ったデータはブートサービス領域にあるかもしれないので，
+    // まずカーネルが所有する領域にコピーし，以降はコピーだけを参照する
+    log::set_logger(&global::LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Trace))
+        .unwrap();
+
+    global::FRAME_BUFFER_CONFIG.set(*fb_config_ref).unwrap();
+    let fb_config = global::frame_buffer_config();
+
+    global::PIXEL_WRITER
+        .set(PixelWriter::new(fb_config))
+        .unwrap();
+    let pixel_writer = global::pixel_writer();
+
+    global::CONSOLE
+        .set(sync::SpinLock::new(console::Console::new(
             pixel_writer,
             DESKTOP_FG_COLOR,
             DESKTOP_BG_COLOR,
-        ));
-        global::MOUSE_CURSOR = Some(mouse::MouseCursor::new(
+        )))
+        .unwrap();
+    global::MOUSE_CURSOR
+        .set(sync::SpinLock::new(mouse::MouseCursor::new(
             pixel_writer,
             DESKTOP_BG_COLOR,
             Vector2D::new(400, 300),
-        ));
-    }
+        )))
+        .unwrap();
 
     let frame_width = fb_config.horizontal_resolution() as i32;
     let frame_height = fb_config.vertical_resolution() as i32;
@@ -206,7 +419,7 @@ pub extern "C" fn KernelMain(
         pixel_writer,
         &Vector2D::new(0, frame_height - 50),
         &Vector2D::new(frame_width, 50),
-        &PixelColor::new(1, 8, 17),
+        &TASKBAR_COLOR,
     );
     fill_rectangle(
         pixel_writer,
@@ -230,32 +443,129 @@ pub extern "C" fn KernelMain(
 
     printk!("Welcome to MikanOS in Rust!\n");
 
-    const AVAILABLE_MEMORY_TYPES: [MemoryType; 3] = [
-        MemoryType::EfiBootServicesCode,
-        MemoryType::EfiBootServicesData,
-        MemoryType::EfiConventionalMemory,
-    ];
+    segment::initialize_segmentation();
+    interrupt::setup_exception_handlers();
+    pic::disable();
 
+    let memory_map = memory_map_ref.copy_to_kernel().unwrap();
     printk!("memory_map: {:p}\n", memory_map);
-    let mut buffer_ptr = memory_map.buffer;
-    while (buffer_ptr as usize as u64) < memory_map.buffer as usize as u64 + memory_map.map_size {
-        let desc = unsafe { &*(buffer_ptr as *const MemoryDescriptor) };
-        let md_type: MemoryType = num::FromPrimitive::from_u32(desc.md_type).unwrap();
-        if AVAILABLE_MEMORY_TYPES.contains(&md_type) {
-            printk!(
-                "type = {}, phys = {:08x} - {:08x}, pages = {}, attr = {:08x}\n",
-                desc.md_type,
-                desc.physical_start as u64,
-                desc.physical_start as u64 + desc.number_of_pages * 4096 - 1,
-                desc.number_of_pages,
-                desc.attribute
-            );
+    // 重なりのあるメモリマップでも，使用中の領域を優先して記録すれば起動は続けられる
+    if let Err(e) = memory_map.validate() {
+        warn!("memory_map: descriptors overlap: {}\n", e);
+    }
+    // ページングの設定などもメモリマネージャを使うので，ロックはこのブロックの中だけで保持する
+    {
+        let mut memory_manager = global::memory_manager();
+        let mut available_end = 0;
+        for desc in memory_map.sorted() {
+            let physical_start = desc.physical_start();
+            if available_end < physical_start {
+                memory_manager.mark_allocated(
+                    FrameID::from_addr(available_end),
+                    ((physical_start - available_end) / BYTES_PER_FRAME) as usize,
+                );
+            }
+
+            if !desc.is_available() {
+                memory_manager.mark_allocated(
+                    FrameID::from_addr(physical_start),
+                    (desc.size() / BYTES_PER_FRAME) as usize,
+                );
+            }
+            // ブートサービス領域は後で再利用するため，管理範囲には含めておく
+            if desc.is_available() || desc.is_reclaimable() {
+                available_end = core::cmp::max(available_end, desc.physical_end());
+            }
         }
-        unsafe {
-            buffer_ptr = buffer_ptr.offset(memory_map.descriptor_size as isize);
+        memory_manager.set_memory_range(FrameID::new(1), FrameID::from_addr(available_end));
+    }
+    for range in memory_map.usable_ranges() {
+        printk!("usable: {}\n", range);
+    }
+    printk!(
+        "memory: usable {} MiB, reclaimable {} MiB, {}\n",
+        memory_map.usable_bytes() / mib(1),
+        memory_map.reclaimable_bytes() / mib(1),
+        global::memory_manager().stat()
+    );
+
+    // UEFI が用意したページテーブルから，カーネルが管理するページテーブルに切り替える
+    let fb_base = fb_config.frame_buffer() as u64;
+    let phys_end = core::cmp::min(
+        memory_map.physical_end(),
+        BitmapMemoryManager::MAX_PHYSICAL_MEMORY_BYTES,
+    );
+    paging::setup_identity_page_table(phys_end).unwrap();
+    paging::map_range(
+        fb_base,
+        fb_base,
+        fb_config.frame_buffer_size(),
+        paging::PageAttr::KERNEL,
+    )
+    .unwrap();
+    paging::activate().unwrap();
+    unsafe {
+        let guard_page = &KERNEL_MAIN_STACK.guard_page as *const u8 as u64;
+        paging::unmap_range(guard_page, paging::PAGE_SIZE_4K).unwrap();
+    }
+    debug!("paging: identity mapped up to {:08x}\n", phys_end);
+
+    local_apic::initialize().unwrap();
+    debug!(
+        "local_apic: {:?}, id = {}, version = {:08x}\n",
+        local_apic::mode(),
+        local_apic::id(),
+        local_apic::version()
+    );
+
+    // RSDP を渡さない古いローダでは acpi_table が不定なので，識別マップした範囲を指すときだけ辿る．
+    // ACPI や I/O APIC が使えなくても，Local APIC タイマと MSI の割り込みだけで動作は続けられる．
+    let rsdp_addr = acpi_table as u64;
+    if rsdp_addr == 0 || rsdp_addr + core::mem::size_of::<acpi::Rsdp>() as u64 > phys_end {
+        warn!(
+            "acpi: invalid RSDP address {:08x}, I/O APIC is not used\n",
+            rsdp_addr
+        );
+    } else if let Err(e) = acpi::initialize(unsafe { &*acpi_table }) {
+        warn!("acpi: {}, I/O APIC is not used\n", e);
+    } else {
+        for table in acpi::tables() {
+            debug!("acpi: {}\n", table);
+        }
+        match ioapic::initialize() {
+            Ok(()) => {
+                for io_apic in ioapic::io_apics() {
+                    debug!("ioapic: {}\n", io_apic);
+                }
+            }
+            Err(e) => warn!("ioapic: {}, I/O APIC is not used\n", e),
         }
     }
 
+    timer::initialize_lapic_timer().unwrap();
+    debug!(
+        "timer: lapic timer freq = {} Hz, tick = {} Hz\n",
+        timer::lapic_timer_freq(),
+        timer::TIMER_FREQ
+    );
+
+    segment::initialize_tss().unwrap();
+    interrupt::setup_ist_handlers();
+
+    heap::initialize_heap(&mut global::memory_manager()).unwrap();
+    printk!("heap: {}\n", heap::stat());
+
+    task::initialize().unwrap();
+
+    // ここまででカーネルのスタック，ページテーブル，GDT，ブートパラメータは
+    // すべてカーネルが所有する領域に移ったので，ブートサービス領域を再利用する
+    let reclaimed_frames = global::memory_manager().reclaim_boot_services_memory(memory_map);
+    printk!(
+        "reclaimed {} MiB of boot services memory, {}\n",
+        reclaimed_frames as u64 * BYTES_PER_FRAME / mib(1),
+        global::memory_manager().stat()
+    );
+
     global::mouse_cursor().refresh();
 
     pci::scan_all_bus().unwrap();
@@ -295,32 +605,25 @@ pub extern "C" fn KernelMain(
         xhc_dev.bus, xhc_dev.device, xhc_dev.function,
     );
 
-    let cs = unsafe { asm::GetCS() };
-    let idt = interrupt::idt();
-    interrupt::set_idt_entry(
-        &mut idt[interrupt::vector::Number::XHCI as usize],
-        interrupt::make_idt_attr(interrupt::DescriptorType::InterruptGate, 0, true, 0),
-        int_handler_xhci as u64,
-        cs,
-    );
-    unsafe {
-        asm::LoadIDT(
-            (core::mem::size_of_val(idt) - 1) as uint16_t,
-            &idt[0] as *const interrupt::InterruptDescriptor as uint64_t,
+    // MSI のメッセージアドレスには 8 ビットの宛先しか書けない
+    let bsp_local_apic_id = u8::try_from(local_apic::id()).unwrap_or_else(|_| {
+        error!(
+            "local APIC ID {} cannot be an MSI destination\n",
+            local_apic::id()
         );
-    }
-
-    let bsp_local_apic_id_addr = 0xfee00020 as *const u32;
-    let bsp_local_apic_id = unsafe { (*bsp_local_apic_id_addr).get_bits(24..=31) as u8 };
-    pci::configure_msi_fixed_destination(
+        loop {
+            hlt()
+        }
+    });
+    let xhci_vectors = pci::configure_msi_vectors(
         xhc_dev,
         bsp_local_apic_id,
         pci::MsiTriggerMode::Level,
-        pci::MsiDeliveryMode::Fixed,
-        interrupt::vector::Number::XHCI as u8,
-        0,
+        &[on_xhci_interrupt],
+        "xhci",
     )
     .unwrap();
+    debug!("xHC interrupt vector = {}\n", xhci_vectors[0]);
 
     let xhc_bar = pci::read_bar(xhc_dev, 0).unwrap();
     debug!("read_bar: Ok\n");
@@ -329,6 +632,9 @@ pub extern "C" fn KernelMain(
     xhc_mmio_base.set_bits(0..=3, 0);
     debug!("xHC mmio_base = {:08x}\n", xhc_mmio_base);
 
+    let xhc_mmio_size = pci::read_bar_size(xhc_dev, 0).unwrap();
+    paging::map_mmio(xhc_mmio_base, xhc_mmio_size).unwrap();
+
     if 0x8086 == pci::read_vendor_id_from_dev(xhc_dev) {
         switch_ehci_to_xhci(xhc_dev);
     }
@@ -338,35 +644,89 @@ pub extern "C" fn KernelMain(
 
         let xhc_handle = driver::UsbInitXhc(xhc_mmio_base);
         driver::print_log();
-        global::XHC_HANDLE = Some(xhc_handle);
+        global::XHC_HANDLE.set(xhc_handle).unwrap();
 
         asm!("sti");
 
-        driver::UsbConfigurePort(xhc_handle, mouse_observer);
+        driver::UsbConfigurePort(xhc_handle, mouse_observer, keyboard_observer, port_observer);
         driver::print_log();
     }
+    task::spawn("xhci", xhci_task, global::xhc_handle() as u64).unwrap();
+    task::spawn("clock", clock_task, 0).unwrap();
+    debug!("dma: {}\n", dma::stat());
 
     loop {
         unsafe {
+            // キューが空であることを確かめてから眠るまでの間にメッセージが届くと，
+            // 起こされないまま眠ってしまう．そこで確認の前に割り込みを禁止しておく．
+            // 割り込みは別のタスクに切り替わった時点で，そのタスクの状態に戻る．
             asm!("cli");
-            let main_queue = global::main_queue();
-            if main_queue.len() == 0 {
-                asm!("sti");
-                asm!("hlt");
-                continue;
-            }
-
-            let msg: Message = main_queue.remove(0);
+            let msg = match task::receive_message() {
+                Some(msg) => msg,
+                None => {
+                    task::sleep_until_message();
+                    asm!("sti");
+                    continue;
+                }
+            };
             asm!("sti");
 
+            if let Some(nmis) = interrupt::take_new_nmis() {
+                warn!("received NMI ({} in total)\n", nmis);
+            }
+            if let Some(overflows) = task::take_dropped_messages() {
+                warn!(
+                    "main task's message queue overflowed ({} messages dropped in total)\n",
+                    overflows
+                );
+            }
+
             #[allow(unreachable_patterns)]
-            match msg.msg_type {
-                MessageType::InterruptXHCI => {
-                    driver::UsbReceiveEvent(global::xhc_handle());
-                    driver::print_log();
+            match msg {
+                Message::TimerTimeout {
+                    id,
+                    value,
+                    overruns,
+                } => {
+                    if overruns > 0 {
+                        warn!("timer {} ({}) overran {} times\n", id, value, overruns);
+                    }
+                    debug!("timer {} ({}) timed out\n", id, value);
+                }
+                Message::MouseMove { displacement, .. } => {
+                    global::mouse_cursor().move_relative(&displacement);
+                }
+                Message::MouseButton { button, pressed } => {
+                    debug!(
+                        "mouse: {:?} {}\n",
+                        button,
+                        if pressed { "down" } else { "up" }
+                    );
+                }
+                // デバッグ用に割り込みの統計を表示する
+                Message::KeyPush {
+                    keycode: keyboard::KEY_F12,
+                    ..
+                } => {
+                    interrupt::print_stats();
+                }
+                Message::KeyPush { keycode, modifier } => {
+                    if let Some(c) = keyboard::keycode_to_ascii(keycode, modifier) {
+                        printk!("{}", c);
+                    }
+                }
+                Message::KeyRelease { .. } => {}
+                Message::DeviceAttached { port } => {
+                    info!("usb: device attached to port {}\n", port);
+                }
+                Message::DeviceDetached { port } => {
+                    info!("usb: device detached from port {}\n", port);
+                }
+                Message::Driver { source, code, data } => {
+                    debug!("{}: event {} ({:016x})\n", source, code, data);
                 }
                 _ => {
-                    error!("Unknown message type: {}\n", msg.msg_type);
+                    error!("Unknown message: {}\n", msg);
                 }
             }
         }
@@ -0,0 +1,268 @@
+//! メモリ管理クラスと周辺機能を集めたファイル．
+#![allow(dead_code)]
+
+use crate::error::{Code, Error};
+use crate::make_error;
+use crate::memory_map::MemoryMap;
+use core::fmt;
+
+pub const fn kib(kib: u64) -> u64 {
+    kib * 1024
+}
+
+pub const fn mib(mib: u64) -> u64 {
+    mib * kib(1024)
+}
+
+pub const fn gib(gib: u64) -> u64 {
+    gib * mib(1024)
+}
+
+/// 物理メモリフレーム 1 つの大きさ（バイト）
+pub const BYTES_PER_FRAME: u64 = kib(4);
+
+/// 物理メモリフレームの番号
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FrameID(usize);
+
+impl FrameID {
+    pub const fn new(id: usize) -> Self {
+        FrameID(id)
+    }
+
+    pub const fn id(&self) -> usize {
+        self.0
+    }
+
+    /// フレームの先頭の物理アドレスを返す
+    pub const fn frame(&self) -> *mut u8 {
+        (self.0 as u64 * BYTES_PER_FRAME) as usize as *mut u8
+    }
+
+    /// 指定された物理アドレスを含むフレームを返す
+    pub const fn from_addr(addr: u64) -> Self {
+        FrameID((addr / BYTES_PER_FRAME) as usize)
+    }
+}
+
+impl fmt::Display for FrameID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+pub const NULL_FRAME: FrameID = FrameID(usize::MAX);
+
+/// メモリの使用状況
+#[derive(Debug, Copy, Clone)]
+pub struct MemoryStat {
+    /// 割り当て済みのフレーム数
+    pub allocated_frames: usize,
+    /// 管理対象のフレーム数
+    pub total_frames: usize,
+}
+
+impl MemoryStat {
+    pub const fn free_frames(&self) -> usize {
+        self.total_frames - self.allocated_frames
+    }
+}
+
+impl fmt::Display for MemoryStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "used {} MiB / free {} MiB / total {} MiB",
+            self.allocated_frames as u64 * BYTES_PER_FRAME / mib(1),
+            self.free_frames() as u64 * BYTES_PER_FRAME / mib(1),
+            self.total_frames as u64 * BYTES_PER_FRAME / mib(1),
+        )
+    }
+}
+
+type MapLineType = u64;
+
+/// このメモリマネージャで扱える最大の物理メモリ量（バイト）
+const MAX_PHYSICAL_MEMORY_BYTES: u64 = gib(128);
+/// MAX_PHYSICAL_MEMORY_BYTES までの物理メモリを扱うために必要なフレーム数
+const FRAME_COUNT: usize = (MAX_PHYSICAL_MEMORY_BYTES / BYTES_PER_FRAME) as usize;
+/// ビットマップ配列の要素型 1 つに含まれるビット数 == フレーム数
+const BITS_PER_MAP_LINE: usize = 8 * core::mem::size_of::<MapLineType>();
+
+/// ビットマップ配列を用いてフレーム単位でメモリ管理するクラス．
+///
+/// 1 ビットを 1 フレームに対応させて，ビットマップにより空きフレームを管理する．
+/// alloc_map の各ビットがフレームに対応し，0 なら空き，1 なら使用中．
+/// alloc_map[n] の m ビット目が対応する物理アドレスは次の式で求まる．
+///   BYTES_PER_FRAME * (n * BITS_PER_MAP_LINE + m)
+pub struct BitmapMemoryManager {
+    /// フレームの空き/空きでないを表すビットマップ
+    alloc_map: [MapLineType; FRAME_COUNT / BITS_PER_MAP_LINE],
+    /// このメモリマネージャで扱うメモリ範囲の始点
+    range_begin: FrameID,
+    /// このメモリマネージャで扱うメモリ範囲の終点．最終フレームの次のフレーム．
+    range_end: FrameID,
+}
+
+impl BitmapMemoryManager {
+    /// このメモリマネージャで扱える最大の物理メモリ量（バイト）
+    pub const MAX_PHYSICAL_MEMORY_BYTES: u64 = MAX_PHYSICAL_MEMORY_BYTES;
+
+    /// インスタンスを初期化する．
+    pub const fn new() -> Self {
+        BitmapMemoryManager {
+            alloc_map: [0; FRAME_COUNT / BITS_PER_MAP_LINE],
+            range_begin: FrameID(0),
+            range_end: FrameID(FRAME_COUNT),
+        }
+    }
+
+    /// 要求されたフレーム数の領域を確保して先頭のフレーム ID を返す
+    pub fn allocate(&mut self, num_frames: usize) -> Result<FrameID, Error> {
+        self.allocate_aligned(num_frames, 1)
+    }
+
+    /// 先頭のフレーム ID が align_frames の倍数となる連続領域を確保する
+    ///
+    /// * `num_frames` - 確保するフレーム数
+    /// * `align_frames` - 先頭フレームのアライメント（フレーム数，2 のべき乗）
+    pub fn allocate_aligned(
+        &mut self,
+        num_frames: usize,
+        align_frames: usize,
+    ) -> Result<FrameID, Error> {
+        if num_frames == 0 || !align_frames.is_power_of_two() {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+
+        let mut start_frame_id = align_up(self.range_begin.id(), align_frames);
+        loop {
+            if start_frame_id + num_frames > self.range_end.id() {
+                return Err(make_error!(Code::NoEnoughMemory));
+            }
+
+            let busy = (0..num_frames)
+                .map(|i| FrameID(start_frame_id + i))
+                .find(|&frame| self.get_bit(frame));
+            match busy {
+                None => {
+                    // num_frames 分の空きが見つかった
+                    self.mark_allocated(FrameID(start_frame_id), num_frames);
+                    return Ok(FrameID(start_frame_id));
+                }
+                Some(frame) => {
+                    // 使用中のフレームの次から再度探す
+                    start_frame_id = align_up(frame.id() + 1, align_frames);
+                }
+            }
+        }
+    }
+
+    /// 確保済みの領域を解放する
+    pub fn free(&mut self, start_frame: FrameID, num_frames: usize) -> Result<(), Error> {
+        if start_frame.id() < self.range_begin.id()
+            || start_frame.id() + num_frames > self.range_end.id()
+        {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+
+        for i in 0..num_frames {
+            self.set_bit(FrameID(start_frame.id() + i), false);
+        }
+        Ok(())
+    }
+
+    /// 指定された領域を使用中として記録する．扱える範囲を超える部分は無視する．
+    pub fn mark_allocated(&mut self, start_frame: FrameID, num_frames: usize) {
+        let end = core::cmp::min(start_frame.id().saturating_add(num_frames), FRAME_COUNT);
+        for id in start_frame.id()..end {
+            self.set_bit(FrameID(id), true);
+        }
+    }
+
+    /// このメモリマネージャで扱うメモリ範囲を設定する．
+    /// この呼び出し以降，allocate によるメモリ割り当ては設定された範囲内でのみ行われる．
+    ///
+    /// * `range_begin` - メモリ範囲の始点
+    /// * `range_end` - メモリ範囲の終点．最終フレームの次のフレーム．
+    pub fn set_memory_range(&mut self, range_begin: FrameID, range_end: FrameID) {
+        self.range_begin = range_begin;
+        self.range_end = if range_end.id() > FRAME_COUNT {
+            FrameID(FRAME_COUNT)
+        } else {
+            range_end
+        };
+    }
+
+    /// ブートサービスが使っていた領域を空きフレームとして登録し，登録したフレーム数を返す
+    ///
+    /// UEFI のページテーブルやローダのスタックなど，ブートサービス領域上のデータを
+    /// 参照しなくなってから呼び出すこと．管理範囲外の部分は無視する．
+    pub fn reclaim_boot_services_memory(&mut self, memory_map: &MemoryMap) -> usize {
+        let mut reclaimed_frames = 0;
+        for desc in memory_map.iter().filter(|desc| desc.is_reclaimable()) {
+            let begin = core::cmp::max(
+                FrameID::from_addr(desc.physical_start()).id(),
+                self.range_begin.id(),
+            );
+            let end = core::cmp::min(
+                FrameID::from_addr(desc.physical_end()).id(),
+                self.range_end.id(),
+            );
+            for id in begin..end {
+                self.set_bit(FrameID(id), false);
+            }
+            reclaimed_frames += end.saturating_sub(begin);
+        }
+        reclaimed_frames
+    }
+
+    /// 空き/使用中フレーム数を返す
+    pub fn stat(&self) -> MemoryStat {
+        let first_line = self.range_begin.id() / BITS_PER_MAP_LINE;
+        let last_line = (self.range_end.id() + BITS_PER_MAP_LINE - 1) / BITS_PER_MAP_LINE;
+        let allocated_frames = (first_line..last_line)
+            .map(|line_index| {
+                let line_begin = line_index * BITS_PER_MAP_LINE;
+                let mut line = self.alloc_map[line_index];
+                // 範囲外のビットは数えない
+                let lower = self.range_begin.id().saturating_sub(line_begin);
+                if lower > 0 {
+                    line &= !0 << lower;
+                }
+                let upper = line_begin + BITS_PER_MAP_LINE;
+                if upper > self.range_end.id() {
+                    line &= !0 >> (upper - self.range_end.id());
+                }
+                line.count_ones() as usize
+            })
+            .sum();
+
+        MemoryStat {
+            allocated_frames,
+            total_frames: self.range_end.id() - self.range_begin.id(),
+        }
+    }
+
+    fn get_bit(&self, frame: FrameID) -> bool {
+        let line_index = frame.id() / BITS_PER_MAP_LINE;
+        let bit_index = frame.id() % BITS_PER_MAP_LINE;
+
+        (self.alloc_map[line_index] & (1 << bit_index)) != 0
+    }
+
+    fn set_bit(&mut self, frame: FrameID, allocated: bool) {
+        let line_index = frame.id() / BITS_PER_MAP_LINE;
+        let bit_index = frame.id() % BITS_PER_MAP_LINE;
+
+        if allocated {
+            self.alloc_map[line_index] |= 1 << bit_index;
+        } else {
+            self.alloc_map[line_index] &= !(1 << bit_index);
+        }
+    }
+}
+
+const fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
@@ -1,5 +1,18 @@
+use crate::error::{Code, Error};
+use crate::make_error;
+use arrayvec::ArrayVec;
 use core::fmt;
 use cty::{uint32_t, uint64_t, uintptr_t};
+use log::warn;
+
+/// UEFI のメモリディスクリプタが用いるページの大きさ（バイト）
+pub const UEFI_PAGE_SIZE: u64 = 4096;
+
+/// sorted() などが扱えるメモリディスクリプタの最大数
+pub const MAX_DESCRIPTORS: usize = 512;
+
+/// カーネルが所有するメモリマップ用バッファの大きさ（バイト）
+const MEMORY_MAP_BUFFER_SIZE: usize = 4096 * 4;
 
 #[repr(C)]
 pub struct MemoryMap {
@@ -21,7 +34,7 @@ pub struct MemoryDescriptor {
 }
 
 #[repr(C)]
-#[derive(FromPrimitive, PartialEq, Eq, Debug)]
+#[derive(FromPrimitive, PartialEq, Eq, Debug, Copy, Clone)]
 pub enum MemoryType {
     EfiReservedMemoryType,
     EfiLoaderCode,
@@ -46,3 +59,249 @@ impl fmt::Display for MemoryType {
         write!(f, "{:?}", self)
     }
 }
+
+const AVAILABLE_MEMORY_TYPES: [MemoryType; 1] = [MemoryType::EfiConventionalMemory];
+
+/// ブートサービスが使っていた領域で，初期化完了後に再利用できるメモリ種別
+const RECLAIMABLE_MEMORY_TYPES: [MemoryType; 2] = [
+    MemoryType::EfiBootServicesCode,
+    MemoryType::EfiBootServicesData,
+];
+
+/// カーネルが起動直後から自由に使って良いメモリ種別なら真を返す
+pub fn is_available(memory_type: MemoryType) -> bool {
+    AVAILABLE_MEMORY_TYPES.contains(&memory_type)
+}
+
+/// 初期化完了後に再利用できるメモリ種別なら真を返す
+pub fn is_reclaimable(memory_type: MemoryType) -> bool {
+    RECLAIMABLE_MEMORY_TYPES.contains(&memory_type)
+}
+
+impl MemoryDescriptor {
+    /// メモリ種別を返す．
+    /// OEM や OS が定義した種別（0x70000000 以上）など，未知の値なら None を返す．
+    pub fn memory_type(&self) -> Option<MemoryType> {
+        num::FromPrimitive::from_u32(self.md_type)
+    }
+
+    pub fn physical_start(&self) -> u64 {
+        self.physical_start as u64
+    }
+
+    /// 領域の終点．最終バイトの次のアドレス．
+    pub fn physical_end(&self) -> u64 {
+        self.physical_start() + self.size()
+    }
+
+    /// 領域の大きさ（バイト）
+    pub fn size(&self) -> u64 {
+        self.number_of_pages * UEFI_PAGE_SIZE
+    }
+
+    /// カーネルが自由に使って良い領域なら真を返す．未知の種別は使えないものとして扱う．
+    pub fn is_available(&self) -> bool {
+        self.memory_type().map_or(false, is_available)
+    }
+
+    /// 初期化完了後に再利用できる領域なら真を返す
+    pub fn is_reclaimable(&self) -> bool {
+        self.memory_type().map_or(false, is_reclaimable)
+    }
+}
+
+impl fmt::Display for MemoryDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.memory_type() {
+            Some(memory_type) => write!(f, "type = {}", memory_type)?,
+            None => write!(f, "type = {:#x}", self.md_type)?,
+        }
+        write!(
+            f,
+            ", phys = {:08x} - {:08x}, pages = {}, attr = {:08x}",
+            self.physical_start(),
+            self.physical_end() - 1,
+            self.number_of_pages,
+            self.attribute
+        )
+    }
+}
+
+/// 物理アドレスの範囲 [start, end)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl MemoryRange {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for MemoryRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x} - {:08x}", self.start, self.end - 1)
+    }
+}
+
+/// メモリマップに含まれるメモリディスクリプタを先頭から順に返すイテレータ
+pub struct MemoryMapIter<'a> {
+    memory_map: &'a MemoryMap,
+    offset: u64,
+}
+
+impl<'a> Iterator for MemoryMapIter<'a> {
+    type Item = &'a MemoryDescriptor;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let descriptor_size = self.memory_map.descriptor_size;
+        if descriptor_size < core::mem::size_of::<MemoryDescriptor>() as u64
+            || self.offset + descriptor_size > self.memory_map.map_size
+        {
+            return None;
+        }
+
+        let desc = unsafe {
+            &*(self.memory_map.buffer.offset(self.offset as isize) as *const MemoryDescriptor)
+        };
+        self.offset += descriptor_size;
+        Some(desc)
+    }
+}
+
+#[repr(C, align(8))]
+struct MemoryMapBuffer([u8; MEMORY_MAP_BUFFER_SIZE]);
+
+/// カーネルが所有するメモリマップのコピー
+static mut MEMORY_MAP_BUFFER: MemoryMapBuffer = MemoryMapBuffer([0; MEMORY_MAP_BUFFER_SIZE]);
+static mut KERNEL_MEMORY_MAP: MemoryMap = MemoryMap {
+    buffer_size: MEMORY_MAP_BUFFER_SIZE as u64,
+    buffer: core::ptr::null(),
+    map_size: 0,
+    map_key: 0,
+    descriptor_size: 0,
+    descriptor_version: 0,
+};
+
+impl MemoryMap {
+    /// メモリマップをカーネルが所有する領域にコピーし，コピーへの参照を返す
+    ///
+    /// UEFI ローダが用意したバッファはブートサービス領域にあるかもしれないため，
+    /// ブートサービス領域を再利用する前にこの関数でコピーしておく．
+    pub fn copy_to_kernel(&self) -> Result<&'static MemoryMap, Error> {
+        let map_size = self.map_size as usize;
+        if map_size > MEMORY_MAP_BUFFER_SIZE {
+            return Err(make_error!(Code::BufferTooSmall));
+        }
+
+        unsafe {
+            let src = core::slice::from_raw_parts(self.buffer, map_size);
+            MEMORY_MAP_BUFFER.0[..map_size].copy_from_slice(src);
+            KERNEL_MEMORY_MAP = MemoryMap {
+                buffer_size: MEMORY_MAP_BUFFER_SIZE as u64,
+                buffer: MEMORY_MAP_BUFFER.0.as_ptr(),
+                map_size: self.map_size,
+                map_key: self.map_key,
+                descriptor_size: self.descriptor_size,
+                descriptor_version: self.descriptor_version,
+            };
+            Ok(&KERNEL_MEMORY_MAP)
+        }
+    }
+
+    /// メモリディスクリプタを格納順に返すイテレータを作る
+    ///
+    /// メモリディスクリプタの大きさは descriptor_size であり，
+    /// MemoryDescriptor の大きさと一致するとは限らない．
+    pub fn iter(&self) -> MemoryMapIter<'_> {
+        MemoryMapIter {
+            memory_map: self,
+            offset: 0,
+        }
+    }
+
+    /// カーネルが起動直後から自由に使えるメモリの総量（バイト）
+    pub fn usable_bytes(&self) -> u64 {
+        self.iter()
+            .filter(|desc| desc.is_available())
+            .map(|desc| desc.size())
+            .sum()
+    }
+
+    /// 初期化完了後に再利用できるメモリの総量（バイト）
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.iter()
+            .filter(|desc| desc.is_reclaimable())
+            .map(|desc| desc.size())
+            .sum()
+    }
+
+    /// すべてのメモリディスクリプタの中で最も大きい終点のアドレス
+    pub fn physical_end(&self) -> u64 {
+        self.iter()
+            .map(|desc| desc.physical_end())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// メモリディスクリプタを物理アドレスの昇順に並べたものを返す
+    ///
+    /// MAX_DESCRIPTORS 個を超える場合は，警告を表示して物理アドレスの小さい方から
+    /// MAX_DESCRIPTORS 個だけを返す．
+    pub fn sorted(&self) -> ArrayVec<&MemoryDescriptor, MAX_DESCRIPTORS> {
+        let mut descs = ArrayVec::<&MemoryDescriptor, MAX_DESCRIPTORS>::new();
+        let mut dropped = 0;
+        for desc in self.iter() {
+            if let Err(e) = descs.try_push(desc) {
+                // 満杯なら，最も後ろにあるものと比べて小さい方を残す
+                dropped += 1;
+                let (last_index, last) = descs
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, d)| d.physical_start())
+                    .unwrap();
+                if e.element().physical_start() < last.physical_start() {
+                    descs[last_index] = e.element();
+                }
+            }
+        }
+        if dropped > 0 {
+            warn!(
+                "memory_map: too many descriptors, ignored {} at the highest addresses\n",
+                dropped
+            );
+        }
+        descs.sort_unstable_by_key(|desc| desc.physical_start());
+        descs
+    }
+
+    /// カーネルが自由に使える領域を昇順に並べ，隣接または重なる領域を結合したものを返す
+    pub fn usable_ranges(&self) -> ArrayVec<MemoryRange, MAX_DESCRIPTORS> {
+        let mut ranges = ArrayVec::<MemoryRange, MAX_DESCRIPTORS>::new();
+        for desc in self.sorted().iter().filter(|desc| desc.is_available()) {
+            match ranges.last_mut() {
+                Some(last) if desc.physical_start() <= last.end => {
+                    last.end = core::cmp::max(last.end, desc.physical_end());
+                }
+                _ => ranges.push(MemoryRange {
+                    start: desc.physical_start(),
+                    end: desc.physical_end(),
+                }),
+            }
+        }
+        ranges
+    }
+
+    /// メモリディスクリプタ同士に重なりがないことを確認する
+    pub fn validate(&self) -> Result<(), Error> {
+        let descs = self.sorted();
+        for pair in descs.windows(2) {
+            if pair[0].physical_end() > pair[1].physical_start() {
+                return Err(make_error!(Code::InvalidDescriptor));
+            }
+        }
+        Ok(())
+    }
+}
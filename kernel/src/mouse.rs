@@ -30,6 +30,45 @@ const MOUSE_CURSOR_SHAPE: [&str; 24] = [
     "         @@@   ",
 ];
 
+/// マウスのボタン
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    pub const ALL: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+    /// ブートプロトコルのレポートの 0 バイト目での位置
+    const fn mask(self) -> u8 {
+        match self {
+            MouseButton::Left => 1 << 0,
+            MouseButton::Right => 1 << 1,
+            MouseButton::Middle => 1 << 2,
+        }
+    }
+}
+
+/// マウスのボタンの押下状態
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct MouseButtons(pub u8);
+
+impl MouseButtons {
+    pub fn is_pressed(&self, button: MouseButton) -> bool {
+        self.0 & button.mask() != 0
+    }
+
+    /// 前の状態から押下状態が変わったボタンを列挙する
+    pub fn changed_from(self, prev: MouseButtons) -> impl Iterator<Item = MouseButton> {
+        MouseButton::ALL
+            .iter()
+            .copied()
+            .filter(move |&button| self.is_pressed(button) != prev.is_pressed(button))
+    }
+}
+
 pub struct MouseCursor<'a> {
     pixel_writer: &'a PixelWriter,
     erase_color: PixelColor,
@@ -0,0 +1,304 @@
+//! メモリページング用のプログラムを集めたファイル．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::error::{Code, Error};
+use crate::global;
+use crate::make_error;
+use crate::memory_manager::{gib, kib, mib};
+use crate::sync::SpinLock;
+use bit_field::BitField;
+
+/// 4 KiB ページの大きさ（バイト）
+pub const PAGE_SIZE_4K: u64 = kib(4);
+/// 2 MiB ページの大きさ（バイト）
+pub const PAGE_SIZE_2M: u64 = mib(2);
+/// 1 GiB ページの大きさ（バイト）
+pub const PAGE_SIZE_1G: u64 = gib(1);
+
+/// 1 つのページテーブルに含まれるエントリ数
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// ページに設定する属性
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageAttr {
+    pub writable: bool,
+    pub user: bool,
+    pub write_through: bool,
+    pub cache_disable: bool,
+}
+
+impl PageAttr {
+    /// カーネルが使う通常のメモリ
+    pub const KERNEL: PageAttr = PageAttr {
+        writable: true,
+        user: false,
+        write_through: false,
+        cache_disable: false,
+    };
+
+    /// キャッシュを無効にした MMIO 領域
+    pub const MMIO: PageAttr = PageAttr {
+        writable: true,
+        user: false,
+        write_through: true,
+        cache_disable: true,
+    };
+}
+
+/// ページマップの 1 エントリ（PML4E, PDPTE, PDE, PTE 共通）
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone)]
+pub struct PageMapEntry(u64);
+
+impl PageMapEntry {
+    const fn zero() -> Self {
+        PageMapEntry(0)
+    }
+
+    pub fn present(&self) -> bool {
+        self.0.get_bit(0)
+    }
+
+    pub fn huge_page(&self) -> bool {
+        self.0.get_bit(7)
+    }
+
+    /// このエントリが指すページまたはページテーブルの物理アドレス
+    pub fn addr(&self) -> u64 {
+        self.0.get_bits(12..=51) << 12
+    }
+
+    pub fn attr(&self) -> PageAttr {
+        PageAttr {
+            writable: self.0.get_bit(1),
+            user: self.0.get_bit(2),
+            write_through: self.0.get_bit(3),
+            cache_disable: self.0.get_bit(4),
+        }
+    }
+
+    fn set(&mut self, addr: u64, attr: PageAttr, huge_page: bool) {
+        let mut value = 0u64;
+        value
+            .set_bit(0, true)
+            .set_bit(1, attr.writable)
+            .set_bit(2, attr.user)
+            .set_bit(3, attr.write_through)
+            .set_bit(4, attr.cache_disable)
+            .set_bit(7, huge_page)
+            .set_bits(12..=51, addr >> 12);
+        self.0 = value;
+    }
+
+    fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+/// 512 エントリからなるページテーブル（PML4, PDPT, PD, PT 共通）
+#[repr(C, align(4096))]
+pub struct PageTable {
+    entries: [PageMapEntry; ENTRIES_PER_TABLE],
+}
+
+/// カーネルが使う PML4 テーブル．ページテーブルを辿る間はこのロックを保持する．
+static PML4_TABLE: SpinLock<Option<&'static mut PageTable>> = SpinLock::new(None);
+
+/// 仮想アドレスから指定された階層のテーブルのインデックスを取り出す
+///
+/// * `level` - 4: PML4, 3: PDPT, 2: PD, 1: PT
+fn page_map_index(addr: u64, level: usize) -> usize {
+    let lsb = 12 + 9 * (level - 1);
+    addr.get_bits(lsb..lsb + 9) as usize
+}
+
+/// 指定された階層のエントリ 1 つが表す領域の大きさ
+const fn page_size_at(level: usize) -> u64 {
+    PAGE_SIZE_4K << (9 * (level - 1))
+}
+
+/// フレームを 1 つ確保し，0 で埋めたページテーブルとして返す
+fn new_page_table() -> Result<*mut PageTable, Error> {
+    let frame = global::memory_manager().allocate(1)?;
+    let table = frame.frame() as *mut PageTable;
+    unsafe {
+        (*table).entries = [PageMapEntry::zero(); ENTRIES_PER_TABLE];
+    }
+    Ok(table)
+}
+
+/// entry が指すページテーブルを返す．
+/// 存在しなければ新たに確保し，巨大ページであれば 1 段下のページに分割する．
+///
+/// * `entry` - 辿るエントリ
+/// * `level` - entry を含むテーブルの階層
+fn next_table(entry: &mut PageMapEntry, level: usize) -> Result<&'static mut PageTable, Error> {
+    if !entry.present() {
+        let table = new_page_table()?;
+        entry.set(table as u64, PageAttr::KERNEL, false);
+    } else if entry.huge_page() {
+        let table = new_page_table()?;
+        let child_size = page_size_at(level - 1);
+        let child_huge = level - 1 > 1;
+        let (base, attr) = (entry.addr(), entry.attr());
+        unsafe {
+            for (i, child) in (*table).entries.iter_mut().enumerate() {
+                child.set(base + child_size * i as u64, attr, child_huge);
+            }
+        }
+        entry.set(table as u64, PageAttr::KERNEL, false);
+    }
+    Ok(unsafe { &mut *(entry.addr() as *mut PageTable) })
+}
+
+/// PML4 テーブルのロックを取得して f を呼び出す．テーブルを作る前なら InvalidPhase を返す．
+fn with_pml4_table<R, F>(f: F) -> Result<R, Error>
+where
+    F: FnOnce(&mut PageTable) -> Result<R, Error>,
+{
+    match PML4_TABLE.lock().as_deref_mut() {
+        Some(pml4_table) => f(pml4_table),
+        None => Err(make_error!(Code::InvalidPhase)),
+    }
+}
+
+/// 指定された仮想アドレスを含む，指定された階層のエントリを返す．
+/// 途中のテーブルは必要に応じて作成または分割する．
+fn walk_create(
+    pml4_table: &mut PageTable,
+    virt_addr: u64,
+    target_level: usize,
+) -> Result<&mut PageMapEntry, Error> {
+    let mut table = pml4_table;
+    for level in ((target_level + 1)..=4).rev() {
+        let entry = &mut table.entries[page_map_index(virt_addr, level)];
+        table = next_table(entry, level)?;
+    }
+    Ok(&mut table.entries[page_map_index(virt_addr, target_level)])
+}
+
+fn invalidate_tlb(virt_addr: u64) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) virt_addr, options(nostack));
+    }
+}
+
+/// 物理アドレス 0 から phys_end までを恒等写像する PML4 テーブルを作る．
+/// 作成したテーブルは activate() を呼ぶまで有効にならない．
+pub fn setup_identity_page_table(phys_end: u64) -> Result<(), Error> {
+    let table = new_page_table()?;
+    *PML4_TABLE.lock() = Some(unsafe { &mut *table });
+
+    let phys_end = (phys_end + PAGE_SIZE_2M - 1) & !(PAGE_SIZE_2M - 1);
+    with_pml4_table(|pml4_table| {
+        let mut addr = 0;
+        while addr < phys_end {
+            walk_create(pml4_table, addr, 2)?.set(addr, PageAttr::KERNEL, true);
+            addr += PAGE_SIZE_2M;
+        }
+        Ok(())
+    })
+}
+
+/// カーネルの PML4 テーブルを CR3 に設定する
+pub fn activate() -> Result<(), Error> {
+    let cr3 = kernel_cr3()?;
+    unsafe {
+        asm::SetCR3(cr3);
+    }
+    Ok(())
+}
+
+/// カーネルの PML4 テーブルのアドレス（CR3 に設定する値）を返す
+pub fn kernel_cr3() -> Result<u64, Error> {
+    with_pml4_table(|pml4_table| Ok(pml4_table as *mut PageTable as u64))
+}
+
+/// 4 KiB ページを 1 つ写像する
+pub fn map_page(virt_addr: u64, phys_addr: u64, attr: PageAttr) -> Result<(), Error> {
+    if virt_addr % PAGE_SIZE_4K != 0 || phys_addr % PAGE_SIZE_4K != 0 {
+        return Err(make_error!(Code::InvalidDescriptor));
+    }
+
+    with_pml4_table(|pml4_table| {
+        walk_create(pml4_table, virt_addr, 1)?.set(phys_addr, attr, false);
+        invalidate_tlb(virt_addr);
+        Ok(())
+    })
+}
+
+/// 連続した領域を 4 KiB ページ単位で写像する
+///
+/// * `virt_addr` - 写像先の仮想アドレス（4 KiB 境界）
+/// * `phys_addr` - 写像元の物理アドレス（4 KiB 境界）
+/// * `size` - 写像する大きさ（バイト）．4 KiB 単位に切り上げる．
+/// * `attr` - ページに設定する属性
+pub fn map_range(virt_addr: u64, phys_addr: u64, size: u64, attr: PageAttr) -> Result<(), Error> {
+    let mut offset = 0;
+    while offset < size {
+        map_page(virt_addr + offset, phys_addr + offset, attr)?;
+        offset += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+/// MMIO 領域をキャッシュ無効で恒等写像し，アクセスに使う仮想アドレスを返す
+///
+/// * `phys_addr` - MMIO 領域の物理アドレス．4 KiB 境界でなくても良い．
+/// * `size` - MMIO 領域の大きさ（バイト）
+pub fn map_mmio(phys_addr: u64, size: u64) -> Result<u64, Error> {
+    let page_begin = phys_addr & !(PAGE_SIZE_4K - 1);
+    let page_end = (phys_addr + size + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+    map_range(
+        page_begin,
+        page_begin,
+        page_end - page_begin,
+        PageAttr::MMIO,
+    )?;
+    Ok(phys_addr)
+}
+
+/// 連続した領域の写像を 4 KiB ページ単位で解除する
+pub fn unmap_range(virt_addr: u64, size: u64) -> Result<(), Error> {
+    if virt_addr % PAGE_SIZE_4K != 0 {
+        return Err(make_error!(Code::InvalidDescriptor));
+    }
+
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt_addr + offset;
+        with_pml4_table(|pml4_table| {
+            if translate_in(pml4_table, addr).is_some() {
+                walk_create(pml4_table, addr, 1)?.clear();
+                invalidate_tlb(addr);
+            }
+            Ok(())
+        })?;
+        offset += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+/// 仮想アドレスを物理アドレスに変換する．写像されていなければ None を返す．
+pub fn translate(virt_addr: u64) -> Option<u64> {
+    with_pml4_table(|pml4_table| Ok(translate_in(pml4_table, virt_addr)))
+        .ok()
+        .flatten()
+}
+
+fn translate_in(pml4_table: &PageTable, virt_addr: u64) -> Option<u64> {
+    let mut table = pml4_table;
+    for level in (1..=4).rev() {
+        let entry = table.entries[page_map_index(virt_addr, level)];
+        if !entry.present() {
+            return None;
+        }
+        if level == 1 || entry.huge_page() {
+            let offset = virt_addr & (page_size_at(level) - 1);
+            return Some(entry.addr() + offset);
+        }
+        table = unsafe { &*(entry.addr() as *const PageTable) };
+    }
+    None
+}
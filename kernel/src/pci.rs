@@ -3,7 +3,10 @@
 
 use crate::asm;
 use crate::error::{Code, Error};
+use crate::interrupt::{self, InterruptVector};
 use crate::make_error;
+use crate::paging;
+use alloc::vec::Vec;
 use bit_field::BitField;
 use core::fmt;
 use cty::uint32_t;
@@ -277,15 +280,29 @@ fn configure_msi_register(
     Ok(())
 }
 
-/// 指定された MSI レジスタを設定する
+/// 指定された MSI-X レジスタを設定する
+///
+/// MSI と同様に，先頭から 2^num_vector_exponent 個（テーブルの大きさが上限）のエントリに
+/// msg_data, msg_data + 1, ... を設定してマスクを解除する．
 fn configure_msix_register(
-    _dev: &Device,
-    _cap_addr: u8,
-    _msg_addr: u32,
-    _msg_data: u32,
-    _num_vector_exponent: u32,
+    dev: &Device,
+    cap_addr: u8,
+    msg_addr: u32,
+    msg_data: u32,
+    num_vector_exponent: u32,
 ) -> Result<(), Error> {
-    Err(make_error!(Code::NotImplemented))
+    let table = MsixTable::new(dev, cap_addr)?;
+    let num_vectors = core::cmp::min(1 << num_vector_exponent, table.num_entries() as u32);
+
+    // 設定中に割り込みが起きないよう，ファンクション全体をマスクしておく
+    table.set_function_mask(true);
+    table.set_enable(true);
+    for index in 0..num_vectors as u16 {
+        table.set_entry(index, msg_addr as u64, msg_data + index as u32)?;
+        table.unmask(index)?;
+    }
+    table.set_function_mask(false);
+    Ok(())
 }
 
 /// CONFIG_ADDRESS に指定された整数を書き込む
@@ -422,6 +439,45 @@ pub fn read_bar(device: &Device, bar_index: u32) -> Result<u64, Error> {
     Ok(ret)
 }
 
+/// BAR が指すメモリ空間の大きさ（バイト）を調べる
+///
+/// BAR に全ビット 1 を書き込んで読み戻すことで大きさを求める．
+/// 調べている間はコマンドレジスタのメモリ空間デコードを無効にする．
+pub fn read_bar_size(device: &Device, bar_index: u32) -> Result<u64, Error> {
+    if bar_index >= 6 {
+        return Err(make_error!(Code::IndexOutOfRange));
+    }
+
+    let addr = calc_bar_address(bar_index);
+    let bar = read_conf_reg(device, addr);
+    let is_64bit = bar.get_bit(2);
+    if is_64bit && bar_index >= 5 {
+        return Err(make_error!(Code::IndexOutOfRange));
+    }
+
+    let command = read_conf_reg(device, 0x04);
+    let mut command_disabled = command;
+    command_disabled.set_bit(1, false); // Memory Space
+    write_conf_reg(device, 0x04, command_disabled);
+
+    write_conf_reg(device, addr, 0xffffffff);
+    let mut mask = read_conf_reg(device, addr) as u64;
+    write_conf_reg(device, addr, bar);
+    if is_64bit {
+        let bar_upper = read_conf_reg(device, addr + 4);
+        write_conf_reg(device, addr + 4, 0xffffffff);
+        mask.set_bits(32..=63, read_conf_reg(device, addr + 4) as u64);
+        write_conf_reg(device, addr + 4, bar_upper);
+    } else {
+        mask.set_bits(32..=63, 0xffffffff);
+    }
+
+    write_conf_reg(device, 0x04, command);
+
+    mask.set_bits(0..=3, 0);
+    Ok(!mask + 1)
+}
+
 /// PCI ケーパビリティレジスタの共通ヘッダ
 #[repr(packed)]
 #[bitfield]
@@ -485,6 +541,18 @@ pub fn configure_msi(
     msg_data: u32,
     num_vector_exponent: u32,
 ) -> Result<(), Error> {
+    let (msi_cap_addr, msix_cap_addr) = find_msi_capabilities(dev);
+    if msi_cap_addr != 0 {
+        configure_msi_register(dev, msi_cap_addr, msg_addr, msg_data, num_vector_exponent)
+    } else if msix_cap_addr != 0 {
+        configure_msix_register(dev, msix_cap_addr, msg_addr, msg_data, num_vector_exponent)
+    } else {
+        Err(make_error!(Code::NoPCIMSI))
+    }
+}
+
+/// MSI と MSI-X のケーパビリティのアドレスを探す．見つからなければ 0 を返す．
+fn find_msi_capabilities(dev: &Device) -> (u8, u8) {
     let mut cap_addr: u8 = read_conf_reg(dev, 0x34).get_bits(0..=7) as u8;
     let mut msi_cap_addr: u8 = 0;
     let mut msix_cap_addr: u8 = 0;
@@ -497,14 +565,29 @@ pub fn configure_msi(
         }
         cap_addr = header.next_ptr();
     }
+    (msi_cap_addr, msix_cap_addr)
+}
 
-    if msi_cap_addr != 0 {
-        configure_msi_register(dev, msi_cap_addr, msg_addr, msg_data, num_vector_exponent)
+/// 1 つのデバイスに割り当てるベクタ数の上限（2^n の n）．MSI の仕様上の上限と同じ 32 個．
+const MAX_MSI_VECTOR_EXPONENT: u32 = 5;
+
+/// デバイスが使えるベクタ数（2^n の n）を返す
+///
+/// configure_msi() と同じく MSI を MSI-X より優先する．
+pub fn msi_vector_exponent(dev: &Device) -> Result<u32, Error> {
+    let (msi_cap_addr, msix_cap_addr) = find_msi_capabilities(dev);
+    let exponent = if msi_cap_addr != 0 {
+        read_msi_capability(dev, msi_cap_addr)
+            .header
+            .multi_msg_capable() as u32
     } else if msix_cap_addr != 0 {
-        configure_msix_register(dev, msi_cap_addr, msg_addr, msg_data, num_vector_exponent)
+        let num_entries = read_msix_capability_header(dev, msix_cap_addr).table_size() + 1;
+        // 2 のべき乗に切り下げる
+        15 - num_entries.leading_zeros()
     } else {
-        Err(make_error!(Code::NoPCIMSI))
-    }
+        return Err(make_error!(Code::NoPCIMSI));
+    };
+    Ok(core::cmp::min(exponent, MAX_MSI_VECTOR_EXPONENT))
 }
 
 #[derive(PartialEq, Eq)]
@@ -544,3 +627,207 @@ pub fn configure_msi_fixed_destination(
 
     configure_msi(dev, msg_addr, msg_data, num_vector_exponent)
 }
+
+#[repr(packed)]
+#[bitfield]
+#[derive(Clone, Copy, Debug)]
+pub struct MsixCapabilityHeader {
+    cap_id: B8,
+    next_ptr: B8,
+    /// テーブルのエントリ数 - 1
+    table_size: B11,
+    #[skip]
+    __: B3,
+    function_mask: bool,
+    msix_enable: bool,
+}
+
+/// MSI-X テーブルのエントリ 1 つの大きさ（バイト）
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+/// MSI-X テーブルと PBA（Pending Bit Array）
+///
+/// テーブルと PBA は BAR が指すメモリ空間に置かれる．ケーパビリティには
+/// どの BAR（BIR）の，どのオフセットに置かれているかが書かれている．
+#[derive(Debug, Copy, Clone)]
+pub struct MsixTable {
+    dev: Device,
+    cap_addr: u8,
+    /// テーブルの先頭アドレス
+    table: u64,
+    /// PBA の先頭アドレス
+    pba: u64,
+    num_entries: u16,
+}
+
+impl MsixTable {
+    /// ケーパビリティを読み取り，テーブルと PBA を写像する
+    fn new(dev: &Device, cap_addr: u8) -> Result<Self, Error> {
+        let header = read_msix_capability_header(dev, cap_addr);
+        let num_entries = header.table_size() + 1;
+
+        let table = map_msix_structure(
+            dev,
+            read_conf_reg(dev, cap_addr + 4),
+            num_entries as u64 * MSIX_ENTRY_SIZE,
+        )?;
+        let pba = map_msix_structure(
+            dev,
+            read_conf_reg(dev, cap_addr + 8),
+            ((num_entries as u64 + 63) / 64) * 8,
+        )?;
+
+        Ok(MsixTable {
+            dev: *dev,
+            cap_addr,
+            table,
+            pba,
+            num_entries,
+        })
+    }
+
+    pub fn num_entries(&self) -> u16 {
+        self.num_entries
+    }
+
+    fn entry_addr(&self, index: u16) -> Result<u64, Error> {
+        if index >= self.num_entries {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        Ok(self.table + index as u64 * MSIX_ENTRY_SIZE)
+    }
+
+    /// エントリにメッセージアドレスとデータを設定する．マスクの状態は変えない．
+    pub fn set_entry(&self, index: u16, msg_addr: u64, msg_data: u32) -> Result<(), Error> {
+        let entry = self.entry_addr(index)?;
+        unsafe {
+            core::ptr::write_volatile(entry as *mut u32, msg_addr.get_bits(0..=31) as u32);
+            core::ptr::write_volatile((entry + 4) as *mut u32, msg_addr.get_bits(32..=63) as u32);
+            core::ptr::write_volatile((entry + 8) as *mut u32, msg_data);
+        }
+        Ok(())
+    }
+
+    /// エントリの割り込みをマスクする
+    pub fn mask(&self, index: u16) -> Result<(), Error> {
+        self.set_masked(index, true)
+    }
+
+    /// エントリの割り込みのマスクを解除する
+    pub fn unmask(&self, index: u16) -> Result<(), Error> {
+        self.set_masked(index, false)
+    }
+
+    fn set_masked(&self, index: u16, masked: bool) -> Result<(), Error> {
+        let vector_control = (self.entry_addr(index)? + 12) as *mut u32;
+        unsafe {
+            let mut value = core::ptr::read_volatile(vector_control);
+            value.set_bit(0, masked);
+            core::ptr::write_volatile(vector_control, value);
+        }
+        Ok(())
+    }
+
+    /// マスク中に発生した割り込みが保留されていれば真を返す
+    pub fn is_pending(&self, index: u16) -> Result<bool, Error> {
+        if index >= self.num_entries {
+            return Err(make_error!(Code::IndexOutOfRange));
+        }
+        let qword = (self.pba + (index as u64 / 64) * 8) as *const u64;
+        Ok(unsafe { core::ptr::read_volatile(qword) }.get_bit(index as usize % 64))
+    }
+
+    /// MSI-X を有効または無効にする
+    pub fn set_enable(&self, enable: bool) {
+        let mut header = read_msix_capability_header(&self.dev, self.cap_addr);
+        header.set_msix_enable(enable);
+        write_msix_capability_header(&self.dev, self.cap_addr, header);
+    }
+
+    /// ファンクション全体の割り込みをマスクする．各エントリのマスクより優先される．
+    pub fn set_function_mask(&self, masked: bool) {
+        let mut header = read_msix_capability_header(&self.dev, self.cap_addr);
+        header.set_function_mask(masked);
+        write_msix_capability_header(&self.dev, self.cap_addr, header);
+    }
+}
+
+fn read_msix_capability_header(dev: &Device, cap_addr: u8) -> MsixCapabilityHeader {
+    let header_data = read_conf_reg(dev, cap_addr);
+    MsixCapabilityHeader::from_bytes(header_data.to_ne_bytes())
+}
+
+fn write_msix_capability_header(dev: &Device, cap_addr: u8, header: MsixCapabilityHeader) {
+    write_conf_reg(dev, cap_addr, u32::from_ne_bytes(header.into_bytes()));
+}
+
+/// BIR とオフセットが示す MSI-X の構造を写像し，先頭アドレスを返す
+///
+/// * `offset_bir` - 下位 3 ビットが BAR の番号，残りがオフセット
+/// * `size` - 構造の大きさ（バイト）
+fn map_msix_structure(dev: &Device, offset_bir: u32, size: u64) -> Result<u64, Error> {
+    let bir = offset_bir.get_bits(0..=2);
+    let offset = (offset_bir & !0b111) as u64;
+
+    let mut base = read_bar(dev, bir)?;
+    if base.get_bit(0) {
+        // I/O 空間の BAR には置けない
+        return Err(make_error!(Code::InvalidDescriptor));
+    }
+    base.set_bits(0..=3, 0);
+    paging::map_mmio(base + offset, size)
+}
+
+/// デバイスの MSI-X ケーパビリティを探し，テーブルを操作するためのオブジェクトを返す
+pub fn find_msix_table(dev: &Device) -> Result<MsixTable, Error> {
+    match find_msi_capabilities(dev) {
+        (_, 0) => Err(make_error!(Code::NoPCIMSI)),
+        (_, msix_cap_addr) => MsixTable::new(dev, msix_cap_addr),
+    }
+}
+
+/// デバイスが使える数だけベクタを確保してハンドラを登録し，MSI または MSI-X を設定する
+///
+/// 確保するベクタ数は handlers.len() とデバイスが使える数のうち小さい方を
+/// 2 のべき乗に切り下げたもの．handlers の先頭から順に各ベクタに割り当て，
+/// 確保したベクタを返す．返されたベクタの数がデバイスの使う割り込みの数になる．
+/// ハンドラには handlers での添字が渡され，EOI は自動で送られる．
+///
+/// * `dev` - 設定対象の PCI デバイス
+/// * `apic_id` - 割り込みの配送先の Local APIC ID
+/// * `trigger_mode` - トリガモード
+/// * `handlers` - 各ベクタに登録する割り込みハンドラ
+/// * `name` - 統計の表示に使う名前
+pub fn configure_msi_vectors(
+    dev: &Device,
+    apic_id: u8,
+    trigger_mode: MsiTriggerMode,
+    handlers: &[interrupt::DispatchFn],
+    name: &'static str,
+) -> Result<Vec<InterruptVector>, Error> {
+    if handlers.is_empty() {
+        return Err(make_error!(Code::IndexOutOfRange));
+    }
+    let requested_exponent = 31 - (handlers.len() as u32).leading_zeros();
+    let exponent = core::cmp::min(requested_exponent, msi_vector_exponent(dev)?);
+
+    let vectors = interrupt::allocate_vector_block(1 << exponent)?;
+    for (index, (vector, handler)) in vectors.iter().zip(handlers).enumerate() {
+        interrupt::install_dispatch(vector, *handler, index, name);
+    }
+
+    if let Err(e) = configure_msi_fixed_destination(
+        dev,
+        apic_id,
+        trigger_mode,
+        MsiDeliveryMode::Fixed,
+        vectors[0].number(),
+        exponent,
+    ) {
+        for vector in vectors {
+            interrupt::release_vector(vector);
+        }
+        return Err(e);
+    }
+    Ok(vectors)
+}
@@ -0,0 +1,151 @@
+//! レガシーな 8259 PIC を無効化するプログラムを集めたファイル．
+//!
+//! 割り込みは Local APIC と I/O APIC で扱うので PIC は使わない．
+//! ただしファームウェアが残した設定のままだと，PIC の IRQ が CPU 例外と同じ
+//! ベクタ 0x08–0x0f に届いてしまう．そこで起動直後にマスタ/スレーブの両方を
+//! 0x20 以降に付け替え，全ラインをマスクする．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::interrupt;
+use crate::segment;
+use bit_field::BitField;
+use core::fmt;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// ICW1: ICW4 あり，カスケード，エッジトリガ，初期化開始
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 モード
+const ICW4_8086: u8 = 0x01;
+/// OCW3: 次の読み出しで ISR（In-Service Register）を返す
+const OCW3_READ_ISR: u8 = 0x0b;
+/// 特定しない EOI
+const EOI: u8 = 0x20;
+
+/// マスタ PIC の IRQ0 を割り当てるベクタ番号
+pub const MASTER_VECTOR_BASE: u8 = 0x20;
+/// スレーブ PIC の IRQ8 を割り当てるベクタ番号
+pub const SLAVE_VECTOR_BASE: u8 = 0x28;
+/// スレーブ PIC を繋いでいるマスタ PIC の IRQ
+const CASCADE_IRQ: u8 = 2;
+
+/// PIC のスプリアス割り込みの回数
+#[derive(Debug, Copy, Clone)]
+pub struct SpuriousStat {
+    /// IRQ7 のスプリアス割り込み
+    pub master: u64,
+    /// IRQ15 のスプリアス割り込み
+    pub slave: u64,
+}
+
+impl fmt::Display for SpuriousStat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spurious IRQ7 = {}, IRQ15 = {}", self.master, self.slave)
+    }
+}
+
+pub fn spurious_stat() -> SpuriousStat {
+    SpuriousStat {
+        master: interrupt::vector_stat(interrupt::vector::Number::PICSpuriousMaster as u8).spurious,
+        slave: interrupt::vector_stat(interrupt::vector::Number::PICSpuriousSlave as u8).spurious,
+    }
+}
+
+/// 古いチップセットが追いつけるよう，使われていないポート 0x80 に書き込んで待つ
+fn io_wait() {
+    unsafe {
+        asm::IoOut8(0x80, 0);
+    }
+}
+
+fn out8(port: u16, data: u8) {
+    unsafe {
+        asm::IoOut8(port, data);
+    }
+    io_wait();
+}
+
+/// 両方の PIC を 0x20–0x2f に付け替えて全ラインをマスクし，スプリアス割り込みのハンドラを登録する
+///
+/// 例外ハンドラを登録した直後，割り込みを許可する前に呼び出すこと．
+pub fn disable() {
+    out8(MASTER_COMMAND, ICW1_INIT);
+    out8(SLAVE_COMMAND, ICW1_INIT);
+    out8(MASTER_DATA, MASTER_VECTOR_BASE); // ICW2: ベクタ番号
+    out8(SLAVE_DATA, SLAVE_VECTOR_BASE);
+    out8(MASTER_DATA, 1 << CASCADE_IRQ); // ICW3: スレーブを繋いでいる IRQ
+    out8(SLAVE_DATA, CASCADE_IRQ); // ICW3: スレーブの ID
+    out8(MASTER_DATA, ICW4_8086);
+    out8(SLAVE_DATA, ICW4_8086);
+
+    // OCW1: 全ラインをマスクする
+    out8(MASTER_DATA, 0xff);
+    out8(SLAVE_DATA, 0xff);
+
+    let idt = interrupt::idt();
+    interrupt::set_idt_entry(
+        &mut idt[interrupt::vector::Number::PICSpuriousMaster as usize],
+        interrupt::make_idt_attr(interrupt::DescriptorType::InterruptGate, 0, true, 0),
+        int_handler_spurious_master as u64,
+        segment::KERNEL_CS,
+    );
+    interrupt::set_idt_entry(
+        &mut idt[interrupt::vector::Number::PICSpuriousSlave as usize],
+        interrupt::make_idt_attr(interrupt::DescriptorType::InterruptGate, 0, true, 0),
+        int_handler_spurious_slave as u64,
+        segment::KERNEL_CS,
+    );
+    interrupt::load_idt();
+    interrupt::set_vector_name(
+        interrupt::vector::Number::PICSpuriousMaster as u8,
+        "pic irq7",
+    );
+    interrupt::set_vector_name(
+        interrupt::vector::Number::PICSpuriousSlave as u8,
+        "pic irq15",
+    );
+}
+
+/// ISR の irq ビットが立っていれば，その IRQ は本当に処理中である
+fn in_service(command_port: u16, irq: u8) -> bool {
+    unsafe {
+        asm::IoOut8(command_port, OCW3_READ_ISR);
+        asm::IoIn8(command_port).get_bit(irq as usize)
+    }
+}
+
+/// マスタ PIC の IRQ7
+///
+/// 全ラインをマスクしていても，PIC は割り込み要求が INTA サイクルまでに取り下げられると
+/// 最も優先度の低い IRQ7 を報告する．これがスプリアス割り込みで，ISR のビットは立たない．
+/// スプリアス割り込みには EOI を送ってはいけない．
+extern "x86-interrupt" fn int_handler_spurious_master(_: *const interrupt::InterruptFrame) {
+    if in_service(MASTER_COMMAND, 7) {
+        unsafe {
+            asm::IoOut8(MASTER_COMMAND, EOI);
+        }
+    } else {
+        interrupt::record_spurious(interrupt::vector::Number::PICSpuriousMaster as u8);
+    }
+}
+
+/// スレーブ PIC の IRQ15
+///
+/// スレーブでスプリアス割り込みが起きた場合，スレーブには EOI を送らないが，
+/// マスタの IRQ2（カスケード）は実際に処理中になっているので，マスタには EOI を送る．
+extern "x86-interrupt" fn int_handler_spurious_slave(_: *const interrupt::InterruptFrame) {
+    if in_service(SLAVE_COMMAND, 7) {
+        unsafe {
+            asm::IoOut8(SLAVE_COMMAND, EOI);
+        }
+    } else {
+        interrupt::record_spurious(interrupt::vector::Number::PICSpuriousSlave as u8);
+    }
+    unsafe {
+        asm::IoOut8(MASTER_COMMAND, EOI);
+    }
+}
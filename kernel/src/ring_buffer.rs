@@ -0,0 +1,166 @@
+//! 割り込みハンドラからメインループへイベントを渡すためのリングバッファを書いたファイル．
+//!
+//! 各スロットに通し番号を持たせ，書き込み位置と読み出し位置をアトミックに進めるだけで
+//! 排他する（D. Vyukov の bounded queue と同じ方式）．ロックを取らないので，
+//! メインループが読み出している途中に割り込みハンドラが書き込んでも止まることはない．
+//! 満杯のときは書き込みに失敗して値を返し，溢れた回数と「捨てた」フラグを記録する．
+//! パニックはしない．
+#![allow(dead_code)]
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+struct Slot<T> {
+    /// このスロットに次に書き込める（あるいは読み出せる）位置
+    ///
+    /// 位置 pos に書き込めるのは sequence == pos のとき，
+    /// 読み出せるのは sequence == pos + 1 のとき．
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    const EMPTY: Self = Slot::new(0);
+
+    const fn new(sequence: usize) -> Self {
+        Slot {
+            sequence: AtomicUsize::new(sequence),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+/// 容量 N の固定長リングバッファ
+///
+/// 書き込み側は複数（割り込みハンドラとメインループなど）でも良い．
+pub struct RingBuffer<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// 次に読み出す位置
+    head: AtomicUsize,
+    /// 次に書き込む位置
+    tail: AtomicUsize,
+    /// 満杯で書き込めなかった回数
+    overflows: AtomicU64,
+    /// 前回 take_dropped() を呼んでから値を捨てたか
+    dropped: AtomicBool,
+}
+
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+unsafe impl<T: Send, const N: usize> Send for RingBuffer<T, N> {}
+
+impl<T, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        let mut slots = [Slot::EMPTY; N];
+        let mut i = 0;
+        while i < N {
+            slots[i] = Slot::new(i);
+            i += 1;
+        }
+        RingBuffer {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overflows: AtomicU64::new(0),
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// 値を末尾に追加する．満杯なら値をそのまま返し，溢れた回数を数える．
+    ///
+    /// 割り込みハンドラから呼び出して良い．
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe {
+                            (*slot.value.get()).as_mut_ptr().write(value);
+                        }
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // 1 周前の値がまだ読み出されていない
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+                self.dropped.store(true, Ordering::Relaxed);
+                return Err(value);
+            } else {
+                // 他の書き込みに追い越された
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 先頭の値を取り出す．空なら None を返す．
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos % N];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).as_ptr().read() };
+                        slot.sequence.store(pos.wrapping_add(N), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // 空，または書き込み途中
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 溜まっている値の数（読み書きと同時に呼ぶと目安にしかならない）
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Relaxed);
+        core::cmp::min(tail.wrapping_sub(head), N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 満杯で書き込めなかった回数の累計
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    /// 前回の呼び出しから値を捨てたかを返し，フラグを下ろす
+    pub fn take_dropped(&self) -> bool {
+        self.dropped.swap(false, Ordering::Relaxed)
+    }
+}
+
+impl<T, const N: usize> Drop for RingBuffer<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
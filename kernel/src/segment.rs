@@ -0,0 +1,194 @@
+//! セグメンテーション用のプログラムを集めたファイル．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::error::Error;
+use crate::global;
+use crate::memory_manager::BYTES_PER_FRAME;
+use bit_field::BitField;
+use modular_bitfield::prelude::*;
+
+#[repr(C)]
+#[derive(BitfieldSpecifier, Debug)]
+#[bits = 4]
+pub enum SegmentType {
+    // data segment types
+    ReadWrite = 2,
+    // code segment types
+    ExecuteRead = 10,
+    // system segment types
+    TSSAvailable = 9,
+    TSSBusy = 11,
+}
+
+#[repr(packed)]
+#[bitfield]
+#[derive(Clone, Copy, Debug)]
+pub struct SegmentDescriptor {
+    limit_low: B16,
+    base_low: B16,
+    base_middle: B8,
+    segment_type: SegmentType,
+    system_segment: bool,
+    descriptor_privilege_level: B2,
+    present: bool,
+    limit_high: B4,
+    available: bool,
+    long_mode: bool,
+    default_operation_size: bool,
+    granularity: bool,
+    base_high: B8,
+}
+
+impl SegmentDescriptor {
+    const fn null() -> Self {
+        SegmentDescriptor::from_bytes([0u8; 8])
+    }
+}
+
+/// 64 ビットモードのタスクステートセグメント
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        TaskStateSegment {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            iomap_base: core::mem::size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_SS: u16 = 2 << 3;
+pub const KERNEL_DS: u16 = 0;
+pub const TSS: u16 = 3 << 3;
+
+/// ダブルフォルト用のスタックを指す IST 番号
+pub const IST_FOR_DOUBLE_FAULT: u8 = 1;
+/// NMI 用のスタックを指す IST 番号
+pub const IST_FOR_NMI: u8 = 2;
+
+/// IST 用スタック 1 つあたりのフレーム数
+const IST_STACK_FRAMES: usize = 8;
+
+static mut GDT: [SegmentDescriptor; 5] = [SegmentDescriptor::null(); 5];
+static mut KERNEL_TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn set_code_segment(
+    desc: &mut SegmentDescriptor,
+    segment_type: SegmentType,
+    descriptor_privilege_level: u8,
+    base: u32,
+    limit: u32,
+) {
+    *desc = SegmentDescriptor::new()
+        .with_base_low(base.get_bits(0..=15) as u16)
+        .with_base_middle(base.get_bits(16..=23) as u8)
+        .with_base_high(base.get_bits(24..=31) as u8)
+        .with_limit_low(limit.get_bits(0..=15) as u16)
+        .with_limit_high(limit.get_bits(16..=19) as u8)
+        .with_segment_type(segment_type)
+        .with_system_segment(true) // 1: code & data segment
+        .with_descriptor_privilege_level(descriptor_privilege_level)
+        .with_present(true)
+        .with_available(false)
+        .with_long_mode(true)
+        .with_default_operation_size(false) // should be 0 when long_mode == 1
+        .with_granularity(true);
+}
+
+fn set_data_segment(
+    desc: &mut SegmentDescriptor,
+    segment_type: SegmentType,
+    descriptor_privilege_level: u8,
+    base: u32,
+    limit: u32,
+) {
+    set_code_segment(desc, segment_type, descriptor_privilege_level, base, limit);
+    desc.set_long_mode(false);
+    desc.set_default_operation_size(true); // 32-bit stack segment
+}
+
+fn set_system_segment(
+    desc: &mut SegmentDescriptor,
+    segment_type: SegmentType,
+    descriptor_privilege_level: u8,
+    base: u32,
+    limit: u32,
+) {
+    set_code_segment(desc, segment_type, descriptor_privilege_level, base, limit);
+    desc.set_system_segment(false);
+    desc.set_long_mode(false);
+    desc.set_granularity(false);
+}
+
+/// GDT を構築して CPU に登録する
+pub fn setup_segments() {
+    unsafe {
+        GDT[0] = SegmentDescriptor::null();
+        set_code_segment(&mut GDT[1], SegmentType::ExecuteRead, 0, 0, 0xfffff);
+        set_data_segment(&mut GDT[2], SegmentType::ReadWrite, 0, 0, 0xfffff);
+        asm::LoadGDT(
+            (core::mem::size_of_val(&GDT) - 1) as u16,
+            &GDT[0] as *const SegmentDescriptor as u64,
+        );
+    }
+}
+
+/// カーネル用の GDT を設定し，各セグメントレジスタに読み込む
+pub fn initialize_segmentation() {
+    setup_segments();
+
+    unsafe {
+        asm::SetDSAll(KERNEL_DS);
+        asm::SetCSSS(KERNEL_CS, KERNEL_SS);
+    }
+}
+
+/// IST 用のスタックを確保し，スタックの最終アドレスを返す
+fn allocate_stack_area(num_frames: usize) -> Result<u64, Error> {
+    let stack = global::memory_manager().allocate(num_frames)?;
+    Ok(stack.frame() as u64 + num_frames as u64 * BYTES_PER_FRAME)
+}
+
+/// TSS を設定して TR に読み込む
+///
+/// ダブルフォルトと NMI は専用のスタック（IST）で処理する．
+/// これによりカーネルスタックが溢れた場合でもダブルフォルトハンドラが動作できる．
+pub fn initialize_tss() -> Result<(), Error> {
+    unsafe {
+        KERNEL_TSS.ist[(IST_FOR_DOUBLE_FAULT - 1) as usize] =
+            allocate_stack_area(IST_STACK_FRAMES)?;
+        KERNEL_TSS.ist[(IST_FOR_NMI - 1) as usize] = allocate_stack_area(IST_STACK_FRAMES)?;
+
+        let tss_addr = &KERNEL_TSS as *const TaskStateSegment as u64;
+        set_system_segment(
+            &mut GDT[(TSS >> 3) as usize],
+            SegmentType::TSSAvailable,
+            0,
+            tss_addr.get_bits(0..=31) as u32,
+            (core::mem::size_of::<TaskStateSegment>() - 1) as u32,
+        );
+        // 64 ビット TSS ディスクリプタの上位 8 バイトにはベースアドレスの上位 32 ビットを置く
+        GDT[(TSS >> 3) as usize + 1] =
+            SegmentDescriptor::from_bytes((tss_addr.get_bits(32..=63)).to_le_bytes());
+
+        asm::LoadTR(TSS);
+    }
+    Ok(())
+}
@@ -0,0 +1,412 @@
+//! 割り込みハンドラやタスクの間でデータを共有するための排他制御と同期の仕組みを集めたファイル．
+//!
+//! SpinLock はロックを保持している間は割り込みを禁止する．これにより，ロックを
+//! 保持したメインの処理に割り込んだハンドラが同じロックを待って止まることはない．
+//! ただし割り込み禁止でも発生する例外と NMI のハンドラではロックを取らないこと．
+//!
+//! Mutex，Semaphore，Event は待つ間タスクを眠らせる．割り込みハンドラで待ってはいけないが，
+//! Semaphore::release() と Event::set() は割り込みハンドラから呼び出して良い．
+#![allow(dead_code)]
+
+use crate::error::{Code, Error};
+use crate::interrupt;
+use crate::make_error;
+use crate::task::{self, TaskID};
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// 保持している間は割り込みを禁止するスピンロック
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// 割り込みを禁止してからロックを取得する．ガードを破棄すると元の割り込み許可状態に戻る．
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        SpinLockGuard {
+            lock: self,
+            interrupts_enabled,
+        }
+    }
+
+    /// ロックが取得できなければ待たずに None を返す
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(SpinLockGuard {
+                lock: self,
+                interrupts_enabled,
+            })
+        } else {
+            interrupt::restore_interrupts(interrupts_enabled);
+            None
+        }
+    }
+
+    /// ロックを強制的に解放する
+    ///
+    /// パニックや例外の報告のように，ロックを保持したまま制御が戻らなくなった
+    /// 状況からデータを使うためのもの．通常の処理では使ってはいけない．
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+/// SpinLock::lock() が返すガード．破棄するとロックを解放する．
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    /// ロックを取得する前に割り込みが許可されていたか
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        interrupt::restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+const ONCE_UNINIT: u8 = 0;
+const ONCE_INITIALIZING: u8 = 1;
+const ONCE_READY: u8 = 2;
+
+/// 一度だけ値を設定でき，以降は共有参照で読み出せるセル
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+unsafe impl<T: Send> Send for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        OnceCell {
+            state: AtomicU8::new(ONCE_UNINIT),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// 値を設定する．既に設定されていればエラーを返す．
+    pub fn set(&self, value: T) -> Result<(), Error> {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_UNINIT,
+                ONCE_INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Err(make_error!(Code::AlreadyAllocated));
+        }
+        unsafe {
+            (*self.value.get()).as_mut_ptr().write(value);
+        }
+        self.state.store(ONCE_READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// 設定済みの値を返す．未設定なら None を返す．
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_READY {
+            Some(unsafe { &*(*self.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+}
+
+/// 待っているタスクの列
+struct WaitQueue {
+    waiters: Vec<TaskID>,
+}
+
+impl WaitQueue {
+    const fn new() -> Self {
+        WaitQueue {
+            waiters: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, id: TaskID) {
+        // 別の理由で起こされて待ち直す場合は，既に並んでいる
+        if !self.waiters.contains(&id) {
+            self.waiters.push(id);
+        }
+    }
+
+    /// 待たずに済んだタスクを列から外す
+    fn remove(&mut self, id: TaskID) {
+        self.waiters.retain(|&waiter| waiter != id);
+    }
+
+    fn wake_one(&mut self) {
+        if !self.waiters.is_empty() {
+            let _ = task::wakeup(self.waiters.remove(0));
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for id in self.waiters.drain(..) {
+            let _ = task::wakeup(id);
+        }
+    }
+}
+
+/// try_acquire() が成功するまで，実行中のタスクを眠らせて待つ
+///
+/// 条件を確かめてから眠るまでの間は割り込みを禁止するので，その間に起こされて
+/// 起床を取りこぼすことはない．
+fn block_until<S, F>(state: &SpinLock<S>, wait_queue: fn(&mut S) -> &mut WaitQueue, try_acquire: F)
+where
+    F: Fn(&mut S) -> bool,
+{
+    let current = task::current_task();
+    loop {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        {
+            let mut state = state.lock();
+            if try_acquire(&mut state) {
+                // 列に残すと，後の wake_one() が走っているこのタスクを起こして無駄になる
+                wait_queue(&mut state).remove(current);
+                drop(state);
+                interrupt::restore_interrupts(interrupts_enabled);
+                return;
+            }
+            wait_queue(&mut state).push(current);
+        }
+        task::sleep_current();
+        interrupt::restore_interrupts(interrupts_enabled);
+    }
+}
+
+struct MutexState {
+    locked: bool,
+    wait_queue: WaitQueue,
+}
+
+/// ロックを待つ間はタスクを眠らせる排他ロック
+///
+/// ロックを保持している間も割り込みとタスクの切り替えは起こる．割り込みハンドラでは使えない．
+pub struct Mutex<T> {
+    state: SpinLock<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex {
+            state: SpinLock::new(MutexState {
+                locked: false,
+                wait_queue: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        block_until(
+            &self.state,
+            |state| &mut state.wait_queue,
+            |state| !core::mem::replace(&mut state.locked, true),
+        );
+        MutexGuard { mutex: self }
+    }
+
+    /// ロックが取得できなければ待たずに None を返す
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        }
+    }
+}
+
+/// Mutex::lock() が返すガード．破棄するとロックを解放し，待っているタスクを 1 つ起こす．
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock();
+        state.locked = false;
+        state.wait_queue.wake_one();
+    }
+}
+
+struct SemaphoreState {
+    count: usize,
+    wait_queue: WaitQueue,
+}
+
+/// 計数セマフォ
+pub struct Semaphore {
+    state: SpinLock<SemaphoreState>,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            state: SpinLock::new(SemaphoreState {
+                count,
+                wait_queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// カウントを 1 減らす．0 なら増えるまで眠って待つ．
+    pub fn acquire(&self) {
+        block_until(&self.state, |state| &mut state.wait_queue, Self::take);
+    }
+
+    /// カウントが 0 なら待たずに false を返す
+    pub fn try_acquire(&self) -> bool {
+        Self::take(&mut self.state.lock())
+    }
+
+    fn take(state: &mut SemaphoreState) -> bool {
+        if state.count > 0 {
+            state.count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// カウントを 1 増やし，待っているタスクを 1 つ起こす．割り込みハンドラから呼び出して良い．
+    pub fn release(&self) {
+        let mut state = self.state.lock();
+        state.count += 1;
+        state.wait_queue.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.state.lock().count
+    }
+}
+
+/// Event を待っていたタスクが進んだ後の振る舞い
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventMode {
+    /// 一度 set() されるとそのまま．待っている，またはこれから待つすべてのタスクが進む．
+    OneShot,
+    /// set() ごとに 1 つのタスクだけが進み，その時点で自動的にリセットされる．
+    /// 待っているタスクがいなければ，次に待つタスクのために保留される．
+    AutoReset,
+}
+
+struct EventState {
+    signaled: bool,
+    wait_queue: WaitQueue,
+}
+
+/// 他のタスクや割り込みハンドラからの通知を待つためのイベント
+pub struct Event {
+    mode: EventMode,
+    state: SpinLock<EventState>,
+}
+
+impl Event {
+    pub const fn new(mode: EventMode) -> Self {
+        Event {
+            mode,
+            state: SpinLock::new(EventState {
+                signaled: false,
+                wait_queue: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// イベントが通知されるまで眠って待つ
+    pub fn wait(&self) {
+        match self.mode {
+            EventMode::OneShot => block_until(
+                &self.state,
+                |state| &mut state.wait_queue,
+                |state| state.signaled,
+            ),
+            EventMode::AutoReset => block_until(
+                &self.state,
+                |state| &mut state.wait_queue,
+                |state| core::mem::replace(&mut state.signaled, false),
+            ),
+        }
+    }
+
+    /// イベントを通知する．割り込みハンドラから呼び出して良い．
+    pub fn set(&self) {
+        let mut state = self.state.lock();
+        state.signaled = true;
+        match self.mode {
+            EventMode::OneShot => state.wait_queue.wake_all(),
+            EventMode::AutoReset => state.wait_queue.wake_one(),
+        }
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.state.lock().signaled
+    }
+}
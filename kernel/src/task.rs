@@ -0,0 +1,588 @@
+//! プリエンプティブなマルチタスクを実現するプログラムを集めたファイル．
+//!
+//! 各タスクは専用のスタックとレジスタの保存領域（TaskContext）を持つ．
+//! タイマ割り込みが SWITCH_TICKS ティックごとに switch_task() を呼び出し，
+//! 実行可能なタスクをラウンドロビンで切り替える．実行可能なタスクがなければ
+//! hlt するだけのアイドルタスクに切り替える．
+//!
+//! KernelMain を実行していたコンテキストは initialize() でメインタスク（MAIN_TASK）になる．
+//!
+//! 各タスクは容量 MESSAGE_QUEUE_CAPACITY のメッセージキューを持ち，send_message() や
+//! send() で ID を指定してメッセージを送れる．マウスやキーボードの入力は
+//! フォーカスを持つタスク（focused_task()）に届く．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::error::{Code, Error};
+use crate::interrupt;
+use crate::make_error;
+use crate::paging;
+use crate::ring_buffer::RingBuffer;
+use crate::segment;
+use crate::sync::{OnceCell, SpinLock, SpinLockGuard};
+use crate::timer;
+use crate::Message;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Waker;
+use cty::c_void;
+
+/// タスク切り替えの間隔（ティック）
+pub const SWITCH_TICKS: u64 = 2;
+/// 各タスクのスタックの大きさ（バイト）
+const TASK_STACK_SIZE: usize = 64 * 1024;
+/// 各タスクのメッセージキューの容量
+pub const MESSAGE_QUEUE_CAPACITY: usize = 32;
+
+/// SwitchContext が保存・復帰するレジスタ．asmfunc.asm のオフセットと合わせること．
+#[repr(C, align(16))]
+struct TaskContext {
+    cr3: u64, // 0x00
+    rip: u64,
+    rflags: u64,
+    reserved1: u64,
+    cs: u64, // 0x20
+    ss: u64,
+    fs: u64,
+    gs: u64,
+    rax: u64, // 0x40
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rdi: u64,
+    rsi: u64,
+    rsp: u64,
+    rbp: u64,
+    r8: u64, // 0x80
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    fxsave_area: [u8; 512], // 0xc0
+}
+
+impl TaskContext {
+    const fn new() -> Self {
+        TaskContext {
+            cr3: 0,
+            rip: 0,
+            rflags: 0,
+            reserved1: 0,
+            cs: 0,
+            ss: 0,
+            fs: 0,
+            gs: 0,
+            rax: 0,
+            rbx: 0,
+            rcx: 0,
+            rdx: 0,
+            rdi: 0,
+            rsi: 0,
+            rsp: 0,
+            rbp: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            fxsave_area: [0; 512],
+        }
+    }
+}
+
+/// タスクの識別子
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskID(u64);
+
+impl fmt::Display for TaskID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// KernelMain を実行していたメインタスク
+pub const MAIN_TASK: TaskID = TaskID(1);
+/// 実行可能なタスクがないときに動くアイドルタスク
+pub const IDLE_TASK: TaskID = TaskID(2);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    /// 実行中，または実行待ち
+    Runnable,
+    /// wakeup() されるまで実行しない
+    Sleeping,
+    /// 終了した．スタックは他のタスクに切り替えた後で解放する．
+    Exited,
+}
+
+/// タスクの本体．引数はタスク自身の ID と spawn() に渡した値．
+pub type TaskEntry = fn(TaskID, u64);
+
+struct Task {
+    name: &'static str,
+    state: TaskState,
+    context: TaskContext,
+    /// メインタスクは KERNEL_MAIN_STACK を使うので持たない
+    stack: Vec<u64>,
+    messages: RingBuffer<Message, MESSAGE_QUEUE_CAPACITY>,
+    /// メッセージが届くのを待って眠っているか
+    waiting_message: bool,
+    /// キューが空くのを待って眠っている送り手
+    blocked_senders: Vec<TaskID>,
+}
+
+impl Task {
+    fn new(name: &'static str, stack: Vec<u64>) -> Box<Self> {
+        Box::new(Task {
+            name,
+            state: TaskState::Runnable,
+            context: TaskContext::new(),
+            stack,
+            messages: RingBuffer::new(),
+            waiting_message: false,
+            blocked_senders: Vec::new(),
+        })
+    }
+}
+
+struct TaskManager {
+    tasks: BTreeMap<TaskID, Box<Task>>,
+    /// 実行待ちのタスク．実行中のタスクとアイドルタスクは含まない．
+    run_queue: VecDeque<TaskID>,
+    current: TaskID,
+    next_id: u64,
+}
+
+static TASK_MANAGER: OnceCell<SpinLock<TaskManager>> = OnceCell::new();
+
+fn task_manager() -> Result<SpinLockGuard<'static, TaskManager>, Error> {
+    TASK_MANAGER
+        .get()
+        .map(|manager| manager.lock())
+        .ok_or(make_error!(Code::InvalidPhase))
+}
+
+impl TaskManager {
+    fn task_mut(&mut self, id: TaskID) -> Result<&mut Task, Error> {
+        self.tasks
+            .get_mut(&id)
+            .map(|task| &mut **task)
+            .ok_or(make_error!(Code::NotFound))
+    }
+
+    fn wake(&mut self, id: TaskID) {
+        if let Ok(task) = self.task_mut(id) {
+            task.waiting_message = false;
+            if task.state == TaskState::Sleeping {
+                task.state = TaskState::Runnable;
+                self.run_queue.push_back(id);
+            }
+        }
+    }
+
+    /// メッセージをキューに積み，メッセージを待っているなら起こす
+    fn push_message(&mut self, id: TaskID, msg: Message) -> Result<(), Error> {
+        let task = self.task_mut(id)?;
+        if task.state == TaskState::Exited {
+            return Err(make_error!(Code::NotFound));
+        }
+        task.messages
+            .push(msg)
+            .map_err(|_| make_error!(Code::Full))?;
+        if core::mem::replace(&mut task.waiting_message, false) {
+            self.wake(id);
+        }
+        Ok(())
+    }
+
+    /// 次に実行するタスクを選び，(次のコンテキスト, 今のコンテキスト) を返す．切り替え不要なら None．
+    fn schedule(&mut self) -> Option<(*const TaskContext, *mut TaskContext)> {
+        self.reap();
+
+        let current = self.current;
+        if current != IDLE_TASK && self.tasks[&current].state == TaskState::Runnable {
+            self.run_queue.push_back(current);
+        }
+        let next = self.run_queue.pop_front().unwrap_or(IDLE_TASK);
+        if next == current {
+            return None;
+        }
+        self.current = next;
+
+        let next_ctx = &self.tasks[&next].context as *const TaskContext;
+        let current_ctx = &mut self.tasks.get_mut(&current).unwrap().context as *mut TaskContext;
+        Some((next_ctx, current_ctx))
+    }
+
+    /// 終了したタスクを解放する．実行中のタスクはまだスタックを使っているので残す．
+    fn reap(&mut self) {
+        let current = self.current;
+        let exited: Vec<TaskID> = self
+            .tasks
+            .iter()
+            .filter(|(&id, task)| id != current && task.state == TaskState::Exited)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in exited {
+            self.tasks.remove(&id);
+        }
+    }
+}
+
+/// 実行中のコンテキストをメインタスクとして登録し，アイドルタスクを作る
+///
+/// ヒープとページングを初期化した後，タイマ割り込みを許可する前に呼び出すこと．
+pub fn initialize() -> Result<(), Error> {
+    let mut manager = TaskManager {
+        tasks: BTreeMap::new(),
+        run_queue: VecDeque::new(),
+        current: MAIN_TASK,
+        next_id: IDLE_TASK.0 + 1,
+    };
+    manager
+        .tasks
+        .insert(MAIN_TASK, Task::new("main", Vec::new()));
+    manager
+        .tasks
+        .insert(IDLE_TASK, new_task("idle", IDLE_TASK, idle_task, 0)?);
+    TASK_MANAGER.set(SpinLock::new(manager))
+}
+
+fn new_task(
+    name: &'static str,
+    id: TaskID,
+    entry: TaskEntry,
+    data: u64,
+) -> Result<Box<Task>, Error> {
+    let mut task = Task::new(name, vec![0; TASK_STACK_SIZE / 8]);
+
+    let stack_end = task.stack.as_ptr() as u64 + TASK_STACK_SIZE as u64;
+    let ctx = &mut task.context;
+    ctx.cr3 = paging::kernel_cr3()?;
+    ctx.rip = task_trampoline as u64;
+    ctx.rdi = entry as usize as u64;
+    ctx.rsi = id.0;
+    ctx.rdx = data;
+    ctx.rflags = 0x202; // IF = 1
+    ctx.cs = segment::KERNEL_CS as u64;
+    ctx.ss = segment::KERNEL_SS as u64;
+    // call 直後と同じく，rsp + 8 が 16 バイト境界になるようにする
+    ctx.rsp = (stack_end & !0xf) - 8;
+    // FPU の制御ワードと MXCSR の既定値（すべての例外をマスク）
+    ctx.fxsave_area[0..2].copy_from_slice(&0x037fu16.to_le_bytes());
+    ctx.fxsave_area[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+    Ok(task)
+}
+
+extern "C" fn task_trampoline(entry: u64, id: u64, data: u64) -> ! {
+    let entry: TaskEntry = unsafe { core::mem::transmute(entry as usize) };
+    entry(TaskID(id), data);
+    exit();
+}
+
+fn idle_task(_: TaskID, _: u64) {
+    loop {
+        unsafe {
+            // 実行可能なタスクがないことを確かめてから hlt するまでの間に起こされると，
+            // 次の割り込みまで眠ってしまう．sti 直後の 1 命令は割り込まれないので sti; hlt とする．
+            asm!("cli");
+            if has_runnable_tasks() {
+                asm!("sti");
+                switch_task();
+            } else {
+                asm!("sti", "hlt");
+            }
+        }
+    }
+}
+
+/// 新しいタスクを作り，実行待ちにする
+///
+/// * `name` - ログに表示するタスクの名前
+/// * `entry` - タスクの本体．戻るとタスクは終了する．
+/// * `data` - entry に渡す値
+pub fn spawn(name: &'static str, entry: TaskEntry, data: u64) -> Result<TaskID, Error> {
+    let id = {
+        let mut manager = task_manager()?;
+        let id = TaskID(manager.next_id);
+        manager.next_id += 1;
+        id
+    };
+    // スタックの確保には時間がかかるので，ロックの外で行う
+    let task = new_task(name, id, entry, data)?;
+    let mut manager = task_manager()?;
+    manager.tasks.insert(id, task);
+    manager.run_queue.push_back(id);
+    Ok(id)
+}
+
+/// 次の実行可能なタスクに切り替える．なければ何もしない．
+///
+/// タイマ割り込みハンドラからは EOI を送った後に呼び出すこと．
+pub fn switch_task() {
+    let interrupts_enabled = interrupt::disable_interrupts();
+    // ガードを破棄してもロックの前に禁止したので割り込みは禁止されたまま
+    let contexts = match task_manager() {
+        Ok(mut manager) => manager.schedule(),
+        Err(_) => None,
+    };
+    if let Some((next_ctx, current_ctx)) = contexts {
+        unsafe {
+            asm::SwitchContext(next_ctx as *const c_void, current_ctx as *mut c_void);
+        }
+    }
+    interrupt::restore_interrupts(interrupts_enabled);
+}
+
+pub fn current_task() -> TaskID {
+    task_manager()
+        .map(|manager| manager.current)
+        .unwrap_or(MAIN_TASK)
+}
+
+pub fn task_name(id: TaskID) -> Option<&'static str> {
+    let manager = task_manager().ok()?;
+    manager.tasks.get(&id).map(|task| task.name)
+}
+
+/// 実行待ちのタスクがあれば真
+pub fn has_runnable_tasks() -> bool {
+    task_manager()
+        .map(|manager| !manager.run_queue.is_empty())
+        .unwrap_or(false)
+}
+
+/// タスクを眠らせる．実行中のタスク自身なら他のタスクに切り替え，起こされると戻る．
+///
+/// 眠る条件を確かめてから眠るまでの間に起こされるのを防ぐには，
+/// 割り込みを禁止した状態で条件を確かめてから呼び出すこと．
+pub fn sleep(id: TaskID) -> Result<(), Error> {
+    if id == IDLE_TASK {
+        return Err(make_error!(Code::InvalidDescriptor));
+    }
+    let interrupts_enabled = interrupt::disable_interrupts();
+    let result = task_manager().and_then(|mut manager| {
+        manager.task_mut(id)?.state = TaskState::Sleeping;
+        manager.run_queue.retain(|&queued| queued != id);
+        Ok(manager.current == id)
+    });
+    if let Ok(true) = result {
+        switch_task();
+    }
+    interrupt::restore_interrupts(interrupts_enabled);
+    result.map(|_| ())
+}
+
+/// 実行中のタスクを眠らせる
+pub fn sleep_current() {
+    let _ = sleep(current_task());
+}
+
+/// timeout_ms ミリ秒の間，実行中のタスクを眠らせる
+///
+/// 期限の前に wakeup() で起こされても，期限を迎えるまで眠り直す．
+pub fn sleep_ms(timeout_ms: u64) {
+    let id = current_task();
+    let deadline = timer::tick() + timer::ms_to_ticks(timeout_ms);
+    loop {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        // 割り込みを禁止しているので，確かめてから眠るまでにタイマが期限を迎えることはない
+        if timer::tick() >= deadline {
+            interrupt::restore_interrupts(interrupts_enabled);
+            return;
+        }
+        let timer = timer::wake_at(deadline, waker(id));
+        sleep_current();
+        // 他の理由で起こされた場合に備えて取り消しておく
+        let _ = timer::cancel_timer(timer);
+        interrupt::restore_interrupts(interrupts_enabled);
+    }
+}
+
+/// 眠っているタスクを実行待ちにする．割り込みハンドラから呼び出して良い．
+pub fn wakeup(id: TaskID) -> Result<(), Error> {
+    let mut manager = task_manager()?;
+    manager.task_mut(id)?;
+    manager.wake(id);
+    Ok(())
+}
+
+/// 実行中のタスクを終了する
+pub fn exit() -> ! {
+    let _ = interrupt::disable_interrupts();
+    if let Ok(mut manager) = task_manager() {
+        let current = manager.current;
+        if let Ok(task) = manager.task_mut(current) {
+            task.state = TaskState::Exited;
+            // キューの空きを待っている送り手は，起きると NotFound を受け取る
+            let senders = core::mem::take(&mut task.blocked_senders);
+            for sender in senders {
+                manager.wake(sender);
+            }
+        }
+    }
+    switch_task();
+    unreachable!("exited task was resumed");
+}
+
+struct TaskWakeup(TaskID);
+
+impl Wake for TaskWakeup {
+    fn wake(self: Arc<Self>) {
+        let _ = wakeup(self.0);
+    }
+}
+
+/// 起こすと id のタスクを wakeup() する Waker を作る
+pub fn waker(id: TaskID) -> Waker {
+    Waker::from(Arc::new(TaskWakeup(id)))
+}
+
+/// id のタスクにメッセージを送る．キューが満杯なら待たずにエラーを返す．
+///
+/// 割り込みハンドラから呼び出して良い．
+pub fn send_message(id: TaskID, msg: Message) -> Result<(), Error> {
+    task_manager()?.push_message(id, msg)
+}
+
+/// id のタスクにメッセージを送る．キューが満杯なら空くまで眠って待つ．
+///
+/// 割り込みハンドラから呼び出してはいけない．
+pub fn send(id: TaskID, msg: Message) -> Result<(), Error> {
+    loop {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        let result = task_manager().and_then(|mut manager| {
+            let current = manager.current;
+            let task = manager.task_mut(id)?;
+            if task.state == TaskState::Exited {
+                return Err(make_error!(Code::NotFound));
+            }
+            if task.messages.len() < task.messages.capacity() {
+                manager.push_message(id, msg)?;
+                return Ok(true);
+            }
+            if id == current {
+                // 自分宛てのキューが空くのを待つと永久に眠ってしまう
+                return Err(make_error!(Code::Full));
+            }
+            if !task.blocked_senders.contains(&current) {
+                task.blocked_senders.push(current);
+            }
+            manager.task_mut(current)?.state = TaskState::Sleeping;
+            Ok(false)
+        });
+        match result {
+            Ok(false) => {
+                switch_task();
+                interrupt::restore_interrupts(interrupts_enabled);
+            }
+            _ => {
+                interrupt::restore_interrupts(interrupts_enabled);
+                return result.map(|_| ());
+            }
+        }
+    }
+}
+
+/// 実行中のタスクのキューからメッセージを取り出す．空なら None を返す．
+pub fn receive_message() -> Option<Message> {
+    let mut manager = task_manager().ok()?;
+    let current = manager.current;
+    let task = manager.task_mut(current).ok()?;
+    let msg = task.messages.pop()?;
+    // 送り手は順に起こし，再びキューの空きを確かめさせる
+    if !task.blocked_senders.is_empty() {
+        let sender = task.blocked_senders.remove(0);
+        manager.wake(sender);
+    }
+    Some(msg)
+}
+
+/// 実行中のタスクのキューが空なら，メッセージが届くか wakeup() されるまで眠る
+///
+/// 割り込みを禁止した状態でキューが空であることを確かめてから呼び出すこと．
+pub fn sleep_until_message() {
+    let interrupts_enabled = interrupt::disable_interrupts();
+    let should_sleep = task_manager()
+        .and_then(|mut manager| {
+            let current = manager.current;
+            let task = manager.task_mut(current)?;
+            task.waiting_message = task.messages.is_empty();
+            Ok(task.waiting_message)
+        })
+        .unwrap_or(false);
+    if should_sleep {
+        sleep_current();
+    }
+    interrupt::restore_interrupts(interrupts_enabled);
+}
+
+/// 実行中のタスクのキューからメッセージを取り出す．空なら届くまで眠って待つ．
+pub fn receive() -> Message {
+    loop {
+        let interrupts_enabled = interrupt::disable_interrupts();
+        let msg = receive_message();
+        if msg.is_none() {
+            sleep_until_message();
+        }
+        interrupt::restore_interrupts(interrupts_enabled);
+        if let Some(msg) = msg {
+            return msg;
+        }
+    }
+}
+
+/// 前回の呼び出しから実行中のタスク宛てのメッセージが溢れていれば，溢れた回数の累計を返す
+pub fn take_dropped_messages() -> Option<u64> {
+    let mut manager = task_manager().ok()?;
+    let current = manager.current;
+    let task = manager.task_mut(current).ok()?;
+    if task.messages.take_dropped() {
+        Some(task.messages.overflows())
+    } else {
+        None
+    }
+}
+
+/// 入力を受け取るタスク
+static FOCUSED_TASK: AtomicU64 = AtomicU64::new(MAIN_TASK.0);
+
+pub fn focused_task() -> TaskID {
+    TaskID(FOCUSED_TASK.load(Ordering::Relaxed))
+}
+
+/// マウスやキーボードの入力を受け取るタスクを変える
+pub fn set_focus(id: TaskID) -> Result<(), Error> {
+    task_manager()?.task_mut(id)?;
+    FOCUSED_TASK.store(id.0, Ordering::Relaxed);
+    Ok(())
+}
+
+/// フォーカスを持つタスクにメッセージを送る．そのタスクが終了していればメインタスクに戻す．
+///
+/// 割り込みハンドラから呼び出して良い．
+pub fn send_to_focused(msg: Message) -> Result<(), Error> {
+    let mut manager = task_manager()?;
+    let mut focused = focused_task();
+    let alive = manager
+        .tasks
+        .get(&focused)
+        .map_or(false, |task| task.state != TaskState::Exited);
+    if !alive {
+        focused = MAIN_TASK;
+        FOCUSED_TASK.store(MAIN_TASK.0, Ordering::Relaxed);
+    }
+    manager.push_message(focused, msg)
+}
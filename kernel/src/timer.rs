@@ -0,0 +1,323 @@
+//! タイマ割り込みと時間管理のプログラムを集めたファイル．
+//!
+//! Local APIC タイマの周波数を PIT（Programmable Interval Timer）で計測し，
+//! TIMER_FREQ Hz の周期割り込みを発生させる．割り込みごとにティックを数え，
+//! task::SWITCH_TICKS ティックごとにタスクを切り替える．
+//!
+//! その上でソフトウェアタイマを提供する．期限を迎えたタイマは
+//! Message::TimerTimeout としてタイマを登録したタスクのキューに届くか，登録した Waker を起こす．
+#![allow(dead_code)]
+
+use crate::asm;
+use crate::error::{Code, Error};
+use crate::interrupt;
+use crate::local_apic::{self, Lvt, LvtEntry, Register};
+use crate::make_error;
+use crate::segment;
+use crate::sync::SpinLock;
+use crate::task;
+use crate::Message;
+use alloc::collections::BTreeMap;
+use bit_field::BitField;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Waker;
+
+/// タイマ割り込みの周波数（Hz）
+pub const TIMER_FREQ: u64 = 100;
+
+/// Local APIC タイマのカウンタの最大値
+const COUNT_MAX: u32 = 0xffff_ffff;
+/// 分周比 1:1 を表す分周設定レジスタの値
+const DIVIDE_BY_1: u32 = 0b1011;
+
+/// PIT の入力クロックの周波数（Hz）
+const PIT_FREQ: u64 = 1_193_182;
+/// 計測に使う時間（ミリ秒）．PIT のカウンタは 16 ビットなので 54 ms 以下にすること．
+const CALIBRATION_MS: u64 = 50;
+
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// NMI ステータス兼制御ポート．ビット 0 がチャネル 2 のゲート，ビット 5 がチャネル 2 の出力．
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+
+/// 起動からのタイマ割り込みの回数
+static TICK: AtomicU64 = AtomicU64::new(0);
+/// 計測した Local APIC タイマの周波数（Hz）
+static LAPIC_TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
+
+/// ソフトウェアタイマの識別子
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerID(u64);
+
+impl fmt::Display for TimerID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Timer {
+    /// 周期（ティック）．0 ならワンショット．
+    period: u64,
+    /// メッセージに載せて返す値
+    value: u64,
+    /// 本来の期限のティック．キューが溢れて再送している間も変えない．
+    deadline: u64,
+    /// メッセージの送り先
+    task: task::TaskID,
+    /// 設定されていれば，メッセージを送る代わりにタスクを起こす
+    waker: Option<Waker>,
+}
+
+/// ソフトウェアタイマを期限順に管理するクラス
+struct TimerManager {
+    /// 動作中のタイマ．キーは (期限のティック, ID) で，先頭が最も早く期限を迎える．
+    timers: BTreeMap<(u64, TimerID), Timer>,
+    /// タイマの ID から期限を引くための表
+    deadlines: BTreeMap<TimerID, u64>,
+    next_id: u64,
+}
+
+static TIMER_MANAGER: SpinLock<TimerManager> = SpinLock::new(TimerManager::new());
+
+impl TimerManager {
+    const fn new() -> Self {
+        TimerManager {
+            timers: BTreeMap::new(),
+            deadlines: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+
+    fn add(&mut self, deadline: u64, period: u64, value: u64, task: task::TaskID) -> TimerID {
+        let id = TimerID(self.next_id);
+        self.next_id += 1;
+        self.insert(
+            deadline,
+            id,
+            Timer {
+                period,
+                value,
+                deadline,
+                task,
+                waker: None,
+            },
+        );
+        id
+    }
+
+    fn add_waker(&mut self, deadline: u64, waker: Waker) -> TimerID {
+        let id = TimerID(self.next_id);
+        self.next_id += 1;
+        self.insert(
+            deadline,
+            id,
+            Timer {
+                period: 0,
+                value: 0,
+                deadline,
+                task: task::MAIN_TASK,
+                waker: Some(waker),
+            },
+        );
+        id
+    }
+
+    fn insert(&mut self, deadline: u64, id: TimerID, timer: Timer) {
+        self.timers.insert((deadline, id), timer);
+        self.deadlines.insert(id, deadline);
+    }
+
+    fn cancel(&mut self, id: TimerID) -> Result<(), Error> {
+        let deadline = self
+            .deadlines
+            .remove(&id)
+            .ok_or(make_error!(Code::InvalidDescriptor))?;
+        self.timers.remove(&(deadline, id));
+        Ok(())
+    }
+
+    /// 期限が now 以前のタイマのメッセージを，タイマを登録したタスクに送る
+    ///
+    /// 周期タイマは元の期限から数えた次の期限で登録し直す．送り先のタスクが終了していれば
+    /// タイマを捨てる．キューが溢れて送れなかったタイマは次のティックで再送し，その間に
+    /// 過ぎた周期の数を届いたメッセージの overruns で報告する．
+    fn process(&mut self, now: u64) {
+        while let Some(&(deadline, id)) = self.timers.keys().next() {
+            if deadline > now {
+                break;
+            }
+            let mut timer = self.timers.remove(&(deadline, id)).unwrap();
+            self.deadlines.remove(&id);
+
+            if let Some(waker) = timer.waker.take() {
+                waker.wake();
+                continue;
+            }
+
+            // 本来の期限から now までに過ぎた周期は，このメッセージにまとめて届ける
+            let overruns = if timer.period > 0 {
+                (now - timer.deadline) / timer.period
+            } else {
+                0
+            };
+            let msg = Message::TimerTimeout {
+                id,
+                value: timer.value,
+                overruns,
+            };
+            match task::send_message(timer.task, msg) {
+                Ok(()) => {}
+                // 送り先のタスクが終了していれば，タイマも捨てる
+                Err(e) if e.code() == Code::NotFound => continue,
+                // タスクの管理が始まる前は送り先がないので，タイマを捨てる
+                Err(e) if e.code() == Code::InvalidPhase => continue,
+                Err(_) => {
+                    interrupt::record_queue_full(interrupt::vector::Number::LAPICTimer as u8);
+                    // 他のタスク宛てのタイマは送れるので，このタイマだけ次のティックで再送する
+                    self.insert(now + 1, id, timer);
+                    continue;
+                }
+            }
+
+            if timer.period > 0 {
+                // 遅れて送った場合でも周期がずれないよう，元の期限から数える
+                timer.deadline += (overruns + 1) * timer.period;
+                self.insert(timer.deadline, id, timer);
+            }
+        }
+    }
+}
+
+/// ミリ秒をティック数に切り上げる．0 にはしない．
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    core::cmp::max((ms * TIMER_FREQ + 999) / 1000, 1)
+}
+
+/// timeout_ms ミリ秒後に一度だけ期限を迎えるタイマを登録する
+///
+/// 期限を迎えると value を載せた Message::TimerTimeout が呼び出したタスクのキューに届く．
+/// task::initialize() より前に期限を迎えたタイマのメッセージは捨てられる．
+pub fn add_timer(timeout_ms: u64, value: u64) -> TimerID {
+    let task = task::current_task();
+    TIMER_MANAGER
+        .lock()
+        .add(tick() + ms_to_ticks(timeout_ms), 0, value, task)
+}
+
+/// period_ms ミリ秒ごとに期限を迎えるタイマを登録する
+pub fn add_periodic_timer(period_ms: u64, value: u64) -> TimerID {
+    let period = ms_to_ticks(period_ms);
+    let task = task::current_task();
+    TIMER_MANAGER
+        .lock()
+        .add(tick() + period, period, value, task)
+}
+
+/// タイマを取り消す．既に期限を迎えたワンショットタイマや，存在しない ID ならエラーを返す．
+///
+/// 取り消す前にキューに積まれたメッセージは取り消されない．
+pub fn cancel_timer(id: TimerID) -> Result<(), Error> {
+    TIMER_MANAGER.lock().cancel(id)
+}
+
+/// timeout_ms ミリ秒後に waker を起こすタイマを登録する
+pub fn wake_after(timeout_ms: u64, waker: Waker) -> TimerID {
+    wake_at(tick() + ms_to_ticks(timeout_ms), waker)
+}
+
+/// ティック数が deadline に達したら waker を起こすタイマを登録する
+pub fn wake_at(deadline: u64, waker: Waker) -> TimerID {
+    TIMER_MANAGER.lock().add_waker(deadline, waker)
+}
+
+/// 起動からのティック数を返す
+pub fn tick() -> u64 {
+    TICK.load(Ordering::Relaxed)
+}
+
+/// 起動からの経過時間（ミリ秒）を返す
+pub fn uptime_ms() -> u64 {
+    tick() * 1000 / TIMER_FREQ
+}
+
+/// 計測した Local APIC タイマの周波数（Hz）を返す
+pub fn lapic_timer_freq() -> u64 {
+    LAPIC_TIMER_FREQ.load(Ordering::Relaxed)
+}
+
+/// Local APIC タイマの周波数を計測し，TIMER_FREQ Hz の周期割り込みを開始する
+///
+/// local_apic::initialize() の後に呼び出すこと．
+pub fn initialize_lapic_timer() -> Result<(), Error> {
+    local_apic::write(Register::TimerDivideConfiguration, DIVIDE_BY_1);
+    local_apic::write_lvt(Lvt::Timer, LvtEntry::new().with_masked(true));
+
+    // ワンショットモードで最大値から数え下げ，PIT で測った一定時間での減少量を求める
+    let elapsed = pit_measure(CALIBRATION_MS, || {
+        local_apic::write(Register::TimerInitialCount, COUNT_MAX);
+    });
+    let elapsed = COUNT_MAX - elapsed;
+    local_apic::write(Register::TimerInitialCount, 0);
+    if elapsed == 0 {
+        return Err(make_error!(Code::InvalidPhase));
+    }
+
+    let freq = elapsed as u64 * 1000 / CALIBRATION_MS;
+    LAPIC_TIMER_FREQ.store(freq, Ordering::Relaxed);
+
+    let idt = interrupt::idt();
+    interrupt::set_idt_entry(
+        &mut idt[interrupt::vector::Number::LAPICTimer as usize],
+        interrupt::make_idt_attr(interrupt::DescriptorType::InterruptGate, 0, true, 0),
+        int_handler_lapic_timer as u64,
+        segment::KERNEL_CS,
+    );
+    interrupt::load_idt();
+    interrupt::set_vector_name(interrupt::vector::Number::LAPICTimer as u8, "lapic timer");
+
+    local_apic::write_lvt(
+        Lvt::Timer,
+        LvtEntry::new()
+            .with_vector(interrupt::vector::Number::LAPICTimer as u8)
+            .with_masked(false)
+            .with_timer_mode(1), // 周期
+    );
+    local_apic::write(Register::TimerInitialCount, (freq / TIMER_FREQ) as u32);
+    Ok(())
+}
+
+/// PIT のチャネル 2 を使って ms ミリ秒を計り，その間の Local APIC タイマの値を返す
+///
+/// * `ms` - 計測する時間（ミリ秒）
+/// * `start` - PIT のカウント開始直前に呼び出す関数．Local APIC タイマを開始する．
+fn pit_measure<F: FnOnce()>(ms: u64, start: F) -> u32 {
+    let count = PIT_FREQ * ms / 1000;
+    unsafe {
+        // ゲートを閉じ，スピーカへの出力を止めておく
+        let mut gate = asm::IoIn8(PIT_CHANNEL2_GATE);
+        gate.set_bit(0, false).set_bit(1, false);
+        asm::IoOut8(PIT_CHANNEL2_GATE, gate);
+
+        // チャネル 2，下位・上位バイトの順に書き込み，モード 0（ワンショット），バイナリ
+        asm::IoOut8(PIT_COMMAND, 0b1011_0000);
+        asm::IoOut8(PIT_CHANNEL2_DATA, count.get_bits(0..=7) as u8);
+        asm::IoOut8(PIT_CHANNEL2_DATA, count.get_bits(8..=15) as u8);
+
+        // ゲートを開くとカウントが始まり，0 になると出力が 1 になる
+        start();
+        asm::IoOut8(PIT_CHANNEL2_GATE, *gate.set_bit(0, true));
+        while !asm::IoIn8(PIT_CHANNEL2_GATE).get_bit(5) {}
+    }
+    local_apic::read(Register::TimerCurrentCount)
+}
+
+extern "x86-interrupt" fn int_handler_lapic_timer(_: *const interrupt::InterruptFrame) {
+    let now = TICK.fetch_add(1, Ordering::Relaxed) + 1;
+    TIMER_MANAGER.lock().process(now);
+    interrupt::notify_end_of_interrupt(interrupt::vector::Number::LAPICTimer as u8);
+    if now % task::SWITCH_TICKS == 0 {
+        task::switch_task();
+    }
+}